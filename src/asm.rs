@@ -5,7 +5,10 @@ pub unsafe fn write_kernel_page_table(_root_paddr: PhysAddr) {}
 #[cfg(feature = "irq")]
 pub use crate::irq::{disable_irqs, enable_irqs, irqs_enabled};
 
+/// Waits for IRQs, see the `dispatch-mode` config for how the primary CPU
+/// waits. Secondary CPUs block on their bound notification.
 #[cfg(feature = "irq")]
 pub fn wait_for_irqs() {
-    sel4::r#yield();
+    crate::irq::wait_for_irqs();
+    crate::sysrq::run_pending();
 }
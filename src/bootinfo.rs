@@ -0,0 +1,196 @@
+//! Boot information handed over by the root task.
+//!
+//! The root task places a [`BootInfo`] in a page shared with this component and
+//! passes its virtual address as the boot argument of `_start`. The platform
+//! takes its capability locations, free slots, heap region and device memory
+//! from it. When no boot information is passed (the argument is 0), the
+//! built-in layout of [`BootInfo::fallback`] is used. Boot information that is
+//! not recognised, or has an empty slot range, is an initialization error.
+
+use core::ops::Range;
+use lazyinit::LazyInit;
+
+use crate::error::{InitResult, PlatformInitError};
+
+/// Magic number identifying a [`BootInfo`], "SEL4BOOT" in ASCII.
+pub const BOOT_INFO_MAGIC: u64 = 0x5345_4c34_424f_4f54;
+
+/// Current version of the [`BootInfo`] layout.
+pub const BOOT_INFO_VERSION: u32 = 3;
+
+/// Maximum number of free slot ranges in a [`BootInfo`].
+pub const MAX_SLOT_RANGES: usize = 4;
+
+/// Maximum number of device untyped caps in a [`BootInfo`].
+pub const MAX_DEVICE_UNTYPED: usize = 16;
+
+/// Maximum number of device frame caps in a [`BootInfo`].
+pub const MAX_DEVICE_FRAMES: usize = 16;
+
+static BOOT_INFO: LazyInit<BootInfo> = LazyInit::new();
+
+/// A range of free slots `[start, end)` in the component's CSpace.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SlotRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+/// A memory region mapped in the component's VSpace.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MemRegion {
+    pub vaddr: u64,
+    pub paddr: u64,
+    pub size: u64,
+}
+
+/// A device untyped cap covering `[paddr, paddr + (1 << size_bits))`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DeviceUntyped {
+    pub cap: u64,
+    pub paddr: u64,
+    pub size_bits: u64,
+}
+
+/// A device frame cap of a 4KB page at `paddr`, not mapped yet.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DeviceFrame {
+    pub cap: u64,
+    pub paddr: u64,
+}
+
+/// Versioned boot information structure shared with the root task.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct BootInfo {
+    /// Must be [`BOOT_INFO_MAGIC`].
+    pub magic: u64,
+    /// Layout version, must be [`BOOT_INFO_VERSION`].
+    pub version: u32,
+    /// Size of the structure in bytes, as written by the root task.
+    pub size: u32,
+    /// Untyped cap used for kernel objects.
+    pub obj_untyped: u64,
+    /// Untyped cap backing the memory space.
+    pub mem_untyped: u64,
+    /// IRQ control cap, or 0 if IRQ handlers are obtained from the root task.
+    pub irq_control: u64,
+    /// Endpoint of the uart-thread server, or 0 if there is none.
+    pub uart_ep: u64,
+    /// Initial heap region, its physical address is looked up from the root
+    /// task if `paddr` is 0.
+    pub heap: MemRegion,
+    pub num_slot_ranges: u64,
+    pub slot_ranges: [SlotRange; MAX_SLOT_RANGES],
+    pub num_device_untyped: u64,
+    pub device_untyped: [DeviceUntyped; MAX_DEVICE_UNTYPED],
+    pub num_device_frames: u64,
+    pub device_frames: [DeviceFrame; MAX_DEVICE_FRAMES],
+}
+
+impl BootInfo {
+    /// Returns the built-in layout used when the root task passes no boot
+    /// information.
+    pub fn fallback() -> Self {
+        let mut info = Self {
+            magic: BOOT_INFO_MAGIC,
+            version: BOOT_INFO_VERSION,
+            size: core::mem::size_of::<Self>() as _,
+            obj_untyped: 23,
+            mem_untyped: 24,
+            irq_control: 0,
+            uart_ep: crate::config::devices::UART_EP_SLOT as _,
+            heap: MemRegion {
+                vaddr: crate::config::plat::INIT_HEAP_BASE as _,
+                paddr: 0,
+                size: crate::config::plat::INIT_HEAP_SIZE as _,
+            },
+            num_slot_ranges: 1,
+            slot_ranges: [SlotRange::default(); MAX_SLOT_RANGES],
+            num_device_untyped: 0,
+            device_untyped: [DeviceUntyped::default(); MAX_DEVICE_UNTYPED],
+            num_device_frames: 0,
+            device_frames: [DeviceFrame::default(); MAX_DEVICE_FRAMES],
+        };
+        info.slot_ranges[0] = SlotRange {
+            start: common::config::DEFAULT_EMPTY_SLOT_INDEX as _,
+            end: 0x1000,
+        };
+        for &(cap, paddr, size_bits) in crate::config::devices::DEVICE_UNTYPED
+            .iter()
+            .take(MAX_DEVICE_UNTYPED)
+        {
+            info.device_untyped[info.num_device_untyped as usize] = DeviceUntyped {
+                cap: cap as _,
+                paddr: paddr as _,
+                size_bits: size_bits as _,
+            };
+            info.num_device_untyped += 1;
+        }
+        info
+    }
+
+    /// Reads the boot information at `arg`, returns [`None`] if it is absent.
+    ///
+    /// # Safety
+    ///
+    /// `arg` must be 0 or the address of a readable mapping of at least
+    /// `size_of::<BootInfo>()` bytes.
+    unsafe fn from_arg(arg: usize) -> InitResult<Option<Self>> {
+        if arg == 0 {
+            return Ok(None);
+        }
+        let info = unsafe { core::ptr::read_volatile(arg as *const Self) };
+        let valid = info.magic == BOOT_INFO_MAGIC
+            && info.version == BOOT_INFO_VERSION
+            && info.size as usize >= core::mem::size_of::<Self>()
+            && info.num_slot_ranges > 0
+            && info.num_slot_ranges as usize <= MAX_SLOT_RANGES
+            && info.num_device_untyped as usize <= MAX_DEVICE_UNTYPED
+            && info.num_device_frames as usize <= MAX_DEVICE_FRAMES
+            && info.slot_ranges[..info.num_slot_ranges as usize]
+                .iter()
+                .all(|r| r.start < r.end);
+        if !valid {
+            return Err(PlatformInitError::BadBootInfo {
+                magic: info.magic,
+                version: info.version,
+            });
+        }
+        Ok(Some(info))
+    }
+
+    /// Returns the free slot ranges.
+    pub fn slot_ranges(&self) -> impl Iterator<Item = Range<usize>> + '_ {
+        self.slot_ranges[..self.num_slot_ranges as usize]
+            .iter()
+            .map(|r| r.start as usize..r.end as usize)
+    }
+
+    /// Returns the device untyped caps.
+    pub fn device_untyped(&self) -> &[DeviceUntyped] {
+        &self.device_untyped[..self.num_device_untyped as usize]
+    }
+
+    /// Returns the device frame caps.
+    pub fn device_frames(&self) -> &[DeviceFrame] {
+        &self.device_frames[..self.num_device_frames as usize]
+    }
+}
+
+/// Takes the boot information from the boot argument, or the fallback layout
+/// if there is none.
+pub(crate) fn init(arg: usize) -> InitResult {
+    let info = unsafe { BootInfo::from_arg(arg) }?.unwrap_or_else(BootInfo::fallback);
+    BOOT_INFO.init_once(info);
+    Ok(())
+}
+
+/// Returns the boot information the platform was configured from.
+pub fn boot_info() -> &'static BootInfo {
+    &BOOT_INFO
+}
@@ -1,32 +1,932 @@
-//! PL011 UART.
+//! Console backends.
+//!
+//! The console is driven by one [`ConsoleBackend`], selected by the
+//! `console-backend` config key:
+//!
+//! * `"uart"`: the board UART at `uart-paddr` (default), mapped from the
+//!   device untyped covering it. Its type is selected by `uart-type`: `"pl011"`
+//!   or `"ns16550"` for 8250/16550-compatible UARTs.
+//! * `"sel4-debug"`: the kernel's `seL4_DebugPutChar`, output only. Requires
+//!   the `sel4-debug` feature and a kernel built with debug syscalls.
+//! * `"virtio"`: the first virtio-console device found on `virtio-mmio-ranges`.
+//!
+//! With the `uart-ipc` feature, console I/O is forwarded to the uart-thread
+//! server instead, so that several seL4 components can share one board UART
+//! without interleaving.
+//!
+//! Input can optionally pass through a [`LineDiscipline`] that provides echo,
+//! line editing and Ctrl-C/Ctrl-D events, see [`set_line_discipline`].
+//!
+//! Magic-key sequences are intercepted on the receive path before any other
+//! input processing, see the `sysrq` config section.
+//!
+//! Output written before the backend is initialized goes to the early console:
+//! it is emitted through `seL4_DebugPutChar` with the `sel4-debug` feature, and
+//! buffered and replayed once the backend is ready otherwise. If the system is
+//! powered off before that, e.g. after a panic in an early init stage, the
+//! buffer is dumped to the UART if it can still be mapped, see [`flush_early`].
 
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+#[cfg(not(feature = "uart-ipc"))]
 use arm_pl011::Pl011Uart;
-use axplat::mem::VirtAddr;
+use axplat::console::ConsoleIf;
+#[cfg(not(feature = "sel4-debug"))]
 use kspin::SpinNoIrq;
 use lazyinit::LazyInit;
-use axplat::console::ConsoleIf;
+#[cfg(feature = "irq")]
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+
+#[cfg(not(feature = "uart-ipc"))]
+use crate::config::devices::{CONSOLE_BACKEND, UART_TYPE};
+use crate::error::{InitResult, PlatformInitError};
+use crate::sysrq::Filtered;
+use crate::utils::lock::IrqLock;
+
+static CONSOLE: LazyInit<IrqLock<Box<dyn ConsoleBackend>>> = LazyInit::new();
+
+/// Size of the buffer holding output written before the console is ready.
+#[cfg(not(feature = "sel4-debug"))]
+const EARLY_BUF_SIZE: usize = 0x1000;
+
+/// Output written before the console backend is initialized.
+#[cfg(not(feature = "sel4-debug"))]
+static EARLY_BUF: SpinNoIrq<EarlyBuf> = SpinNoIrq::new(EarlyBuf::new());
+
+/// Fixed-size buffer for early output, bytes beyond its capacity are dropped.
+#[cfg(not(feature = "sel4-debug"))]
+struct EarlyBuf {
+    buf: [u8; EARLY_BUF_SIZE],
+    len: usize,
+    truncated: bool,
+}
+
+#[cfg(not(feature = "sel4-debug"))]
+impl EarlyBuf {
+    const fn new() -> Self {
+        Self {
+            buf: [0; EARLY_BUF_SIZE],
+            len: 0,
+            truncated: false,
+        }
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        let n = bytes.len().min(EARLY_BUF_SIZE - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&bytes[..n]);
+        self.len += n;
+        self.truncated |= n < bytes.len();
+    }
+}
+
+/// Writes bytes before the console backend is initialized.
+#[cfg(feature = "sel4-debug")]
+fn early_write_bytes(bytes: &[u8]) {
+    for c in bytes {
+        sel4::debug_put_char(*c);
+    }
+}
+
+/// Writes bytes before the console backend is initialized.
+#[cfg(not(feature = "sel4-debug"))]
+fn early_write_bytes(bytes: &[u8]) {
+    let mut early = EARLY_BUF.lock();
+    // The backend may have been initialized while waiting for the lock.
+    if CONSOLE.is_inited() {
+        CONSOLE.lock().write_bytes(bytes);
+    } else {
+        early.push(bytes);
+    }
+}
+
+/// Dumps the output buffered by the early console to the UART at `uart-paddr`
+/// if the console backend was never initialized.
+///
+/// It is called on the way to powering off. The UART is mapped from its device
+/// untyped like the backends do, but not initialized, so it keeps the setup
+/// left by the firmware. Nothing is flushed if the memory space is not set up
+/// yet or the UART cannot be mapped.
+pub(crate) fn flush_early() {
+    #[cfg(not(any(feature = "sel4-debug", feature = "uart-ipc")))]
+    {
+        use crate::config::devices::UART_PADDR;
+
+        if CONSOLE.is_inited() || !crate::mem::MEM_SPACE.is_inited() {
+            return;
+        }
+        // the lock may be held by the code that failed
+        let Some(mut early) = EARLY_BUF.try_lock() else {
+            return;
+        };
+        let Some(uart_type) = console_uart_type() else {
+            return;
+        };
+        let Ok(base) = crate::mem::map_device(UART_PADDR, 0x1000) else {
+            return;
+        };
+        let bytes = &early.buf[..early.len];
+        match uart_type {
+            "pl011" => Pl011Backend(Pl011Uart::new(base as *mut u8)).write_bytes(bytes),
+            "ns16550" => Ns16550Backend { base }.write_bytes(bytes),
+            _ => return,
+        }
+        early.len = 0;
+    }
+}
+
+/// A device the console reads from and writes to.
+trait ConsoleBackend: Send {
+    /// Writes a byte to the device.
+    fn putchar(&mut self, c: u8);
+
+    /// Reads a byte from the device, or returns [`None`] if no input is available.
+    fn getchar(&mut self) -> Option<u8>;
+
+    /// Writes given bytes to the device.
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        for c in bytes {
+            self.putchar(*c);
+        }
+    }
+
+    /// Returns the IRQ number raised on input, if the device has one.
+    fn irq_num(&self) -> Option<usize> {
+        None
+    }
+
+    /// Acknowledges a device interrupt, returns `true` if input is pending.
+    fn ack_irq(&mut self) -> bool {
+        true
+    }
+}
+
+/// PL011 UART.
+#[cfg(not(feature = "uart-ipc"))]
+struct Pl011Backend(Pl011Uart);
+
+#[cfg(not(feature = "uart-ipc"))]
+impl Pl011Backend {
+    fn new() -> InitResult<Self> {
+        use crate::config::devices::UART_PADDR;
+        let uart_base = crate::mem::map_device(UART_PADDR, 0x1000)?;
+        let mut uart = Pl011Uart::new(uart_base as *mut u8);
+        uart.init();
+        Ok(Self(uart))
+    }
+}
+
+#[cfg(not(feature = "uart-ipc"))]
+impl ConsoleBackend for Pl011Backend {
+    fn putchar(&mut self, c: u8) {
+        match c {
+            b'\n' => {
+                self.0.putchar(b'\r');
+                self.0.putchar(b'\n');
+            }
+            c => self.0.putchar(c),
+        }
+    }
+
+    fn getchar(&mut self) -> Option<u8> {
+        self.0.getchar()
+    }
+
+    fn irq_num(&self) -> Option<usize> {
+        Some(crate::config::devices::UART_IRQ)
+    }
+
+    fn ack_irq(&mut self) -> bool {
+        if !self.0.is_receive_interrupt() {
+            return false;
+        }
+        self.0.ack_interrupts();
+        true
+    }
+}
+
+/// 8250/16550-compatible UART.
+///
+/// Registers are `1 << uart-reg-shift` bytes apart and accessed with
+/// `uart-reg-width` bytes wide accesses. The baud divisor is programmed from
+/// `uart-clock-hz` and `uart-baud`, or left as set by the firmware when
+/// `uart-baud` is 0.
+#[cfg(not(feature = "uart-ipc"))]
+struct Ns16550Backend {
+    base: usize,
+}
+
+#[cfg(not(feature = "uart-ipc"))]
+impl Ns16550Backend {
+    const RBR_THR_DLL: usize = 0;
+    const IER_DLM: usize = 1;
+    const IIR_FCR: usize = 2;
+    const LCR: usize = 3;
+    const MCR: usize = 4;
+    const LSR: usize = 5;
+
+    const LCR_DLAB: u8 = 0x80;
+    const LCR_8N1: u8 = 0x03;
+    const LSR_DATA_READY: u8 = 0x01;
+    const LSR_THR_EMPTY: u8 = 0x20;
+    const IIR_NO_INT: u8 = 0x01;
+    const IIR_ID_MASK: u8 = 0x0e;
+    const IIR_RX_DATA: u8 = 0x04;
+    const IIR_RX_TIMEOUT: u8 = 0x0c;
+
+    fn new() -> InitResult<Self> {
+        use crate::config::devices::{UART_BAUD, UART_CLOCK_HZ, UART_PADDR, UART_REG_WIDTH};
+        if !matches!(UART_REG_WIDTH, 1 | 4) {
+            return Err(PlatformInitError::InvalidConfig("uart-reg-width must be 1 or 4"));
+        }
+        let divisor = match UART_CLOCK_HZ.checked_div(16 * UART_BAUD) {
+            Some(divisor @ 1..=0xffff) => Some(divisor),
+            Some(_) => {
+                return Err(PlatformInitError::InvalidConfig(
+                    "uart-baud does not fit the divisor of uart-clock-hz",
+                ));
+            }
+            None => None,
+        };
+        let base = crate::mem::map_device(UART_PADDR, 0x1000)?;
+        let uart = Self { base };
+
+        // disable interrupts while programming the line
+        uart.write_reg(Self::IER_DLM, 0);
+        if let Some(divisor) = divisor {
+            uart.write_reg(Self::LCR, Self::LCR_DLAB);
+            uart.write_reg(Self::RBR_THR_DLL, divisor as u8);
+            uart.write_reg(Self::IER_DLM, (divisor >> 8) as u8);
+        }
+        uart.write_reg(Self::LCR, Self::LCR_8N1);
+        // enable and clear FIFOs
+        uart.write_reg(Self::IIR_FCR, 0x07);
+        // DTR, RTS and OUT2, which gates the interrupt line
+        uart.write_reg(Self::MCR, 0x0b);
+        // enable the receive data available interrupt
+        uart.write_reg(Self::IER_DLM, 0x01);
+        Ok(uart)
+    }
+
+    fn reg_addr(&self, reg: usize) -> usize {
+        self.base + (reg << crate::config::devices::UART_REG_SHIFT)
+    }
+
+    fn read_reg(&self, reg: usize) -> u8 {
+        let addr = self.reg_addr(reg);
+        unsafe {
+            // the width is checked to be 1 or 4 by `new`
+            match crate::config::devices::UART_REG_WIDTH {
+                4 => core::ptr::read_volatile(addr as *const u32) as u8,
+                _ => core::ptr::read_volatile(addr as *const u8),
+            }
+        }
+    }
+
+    fn write_reg(&self, reg: usize, val: u8) {
+        let addr = self.reg_addr(reg);
+        unsafe {
+            match crate::config::devices::UART_REG_WIDTH {
+                4 => core::ptr::write_volatile(addr as *mut u32, val as u32),
+                _ => core::ptr::write_volatile(addr as *mut u8, val),
+            }
+        }
+    }
+
+    fn write_byte(&self, c: u8) {
+        while self.read_reg(Self::LSR) & Self::LSR_THR_EMPTY == 0 {
+            core::hint::spin_loop();
+        }
+        self.write_reg(Self::RBR_THR_DLL, c);
+    }
+}
+
+#[cfg(not(feature = "uart-ipc"))]
+impl ConsoleBackend for Ns16550Backend {
+    fn putchar(&mut self, c: u8) {
+        if c == b'\n' {
+            self.write_byte(b'\r');
+        }
+        self.write_byte(c);
+    }
+
+    fn getchar(&mut self) -> Option<u8> {
+        if self.read_reg(Self::LSR) & Self::LSR_DATA_READY != 0 {
+            Some(self.read_reg(Self::RBR_THR_DLL))
+        } else {
+            None
+        }
+    }
+
+    fn irq_num(&self) -> Option<usize> {
+        Some(crate::config::devices::UART_IRQ)
+    }
+
+    fn ack_irq(&mut self) -> bool {
+        // reading IIR acknowledges the interrupt
+        let iir = self.read_reg(Self::IIR_FCR);
+        if iir & Self::IIR_NO_INT != 0 {
+            return false;
+        }
+        matches!(iir & Self::IIR_ID_MASK, Self::IIR_RX_DATA | Self::IIR_RX_TIMEOUT)
+    }
+}
+
+/// Output through the kernel's `seL4_DebugPutChar`.
+#[cfg(all(feature = "sel4-debug", not(feature = "uart-ipc")))]
+struct Sel4DebugBackend;
+
+#[cfg(all(feature = "sel4-debug", not(feature = "uart-ipc")))]
+impl ConsoleBackend for Sel4DebugBackend {
+    fn putchar(&mut self, c: u8) {
+        sel4::debug_put_char(c);
+    }
+
+    fn getchar(&mut self) -> Option<u8> {
+        None
+    }
+}
+
+/// virtio-console device on a virtio-mmio transport.
+#[cfg(not(feature = "uart-ipc"))]
+mod virtio {
+    use super::ConsoleBackend;
+    use axplat::mem::{pa, phys_to_virt};
+    use common::root::translate_addr;
+    use core::alloc::Layout;
+    use core::ptr::NonNull;
+    use virtio_drivers::{
+        BufferDirection, Hal, PAGE_SIZE, PhysAddr,
+        device::console::VirtIOConsole,
+        transport::{DeviceType, Transport, mmio::{MmioTransport, VirtIOHeader}},
+    };
+
+    use crate::config::devices::VIRTIO_MMIO_RANGES;
+
+    pub(super) struct VirtioBackend(VirtIOConsole<VirtIoHalImpl, MmioTransport>);
+
+    impl VirtioBackend {
+        /// Maps `virtio-mmio-ranges` and probes them for the first virtio-console device.
+        ///
+        /// Ranges not covered by a device untyped are skipped.
+        pub(super) fn probe() -> Option<Self> {
+            for &(paddr, size) in VIRTIO_MMIO_RANGES.iter() {
+                let Ok(vaddr) = crate::mem::map_device(paddr, size) else {
+                    continue;
+                };
+                let Some(header) = NonNull::new(vaddr as *mut VirtIOHeader) else {
+                    continue;
+                };
+                let Ok(transport) = (unsafe { MmioTransport::new(header) }) else {
+                    continue;
+                };
+                if transport.device_type() == DeviceType::Console {
+                    return VirtIOConsole::new(transport).ok().map(Self);
+                }
+            }
+            None
+        }
+    }
+
+    impl ConsoleBackend for VirtioBackend {
+        fn putchar(&mut self, c: u8) {
+            if c == b'\n' {
+                let _ = self.0.send(b'\r');
+            }
+            let _ = self.0.send(c);
+        }
 
-static UART: LazyInit<SpinNoIrq<Pl011Uart>> = LazyInit::new();
+        fn getchar(&mut self) -> Option<u8> {
+            self.0.recv(true).ok().flatten()
+        }
 
-fn do_putchar(uart: &mut Pl011Uart, c: u8) {
-    match c {
-        b'\n' => {
-            uart.putchar(b'\r');
-            uart.putchar(b'\n');
+        fn ack_irq(&mut self) -> bool {
+            self.0.ack_interrupt().unwrap_or(false)
         }
-        c => uart.putchar(c),
+    }
+
+    /// DMA memory comes from the heap, translated by the root task.
+    pub(super) struct VirtIoHalImpl;
+
+    fn dma_layout(pages: usize) -> Layout {
+        Layout::from_size_align(pages * PAGE_SIZE, PAGE_SIZE).unwrap()
+    }
+
+    unsafe impl Hal for VirtIoHalImpl {
+        fn dma_alloc(pages: usize, _direction: BufferDirection) -> (PhysAddr, NonNull<u8>) {
+            let vaddr = unsafe { alloc::alloc::alloc_zeroed(dma_layout(pages)) };
+            let vaddr = NonNull::new(vaddr).expect("failed to allocate virtio DMA memory");
+            (translate_addr(vaddr.as_ptr() as usize), vaddr)
+        }
+
+        unsafe fn dma_dealloc(_paddr: PhysAddr, vaddr: NonNull<u8>, pages: usize) -> i32 {
+            unsafe { alloc::alloc::dealloc(vaddr.as_ptr(), dma_layout(pages)) };
+            0
+        }
+
+        unsafe fn mmio_phys_to_virt(paddr: PhysAddr, _size: usize) -> NonNull<u8> {
+            NonNull::new(phys_to_virt(pa!(paddr)).as_mut_ptr()).unwrap()
+        }
+
+        unsafe fn share(buffer: NonNull<[u8]>, _direction: BufferDirection) -> PhysAddr {
+            translate_addr(buffer.as_ptr() as *mut u8 as usize)
+        }
+
+        unsafe fn unshare(_paddr: PhysAddr, _buffer: NonNull<[u8]>, _direction: BufferDirection) {}
     }
 }
 
+/// Console I/O through the uart-thread IPC server.
+#[cfg(feature = "uart-ipc")]
+struct UartIpcBackend;
+
+#[cfg(feature = "uart-ipc")]
+impl ConsoleBackend for UartIpcBackend {
+    fn putchar(&mut self, c: u8) {
+        crate::ipc::uart::write_bytes(&[c]);
+    }
+
+    fn getchar(&mut self) -> Option<u8> {
+        let mut c = [0u8];
+        (crate::ipc::uart::read_bytes(&mut c) == 1).then_some(c[0])
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        crate::ipc::uart::write_bytes(bytes);
+    }
+}
+
+/// Size of the receive ring buffer, must be a power of two.
+#[cfg(feature = "irq")]
+const RX_BUF_SIZE: usize = 256;
+
+/// Bytes received by the console interrupt handler and not yet consumed.
+#[cfg(feature = "irq")]
+static RX_BUF: RxRing = RxRing::new();
+
+/// Whether [`ConsoleIf::read_bytes`] waits for input when the buffer is empty.
+#[cfg(feature = "irq")]
+static RX_BLOCKING: AtomicBool = AtomicBool::new(false);
+
+/// Lock-free single-producer single-consumer ring buffer.
+///
+/// The producer is the console interrupt handler, the consumer is
+/// [`ConsoleIf::read_bytes`]. When the buffer is full, new bytes are dropped.
+#[cfg(feature = "irq")]
+struct RxRing {
+    buf: [AtomicU8; RX_BUF_SIZE],
+    head: AtomicUsize,
+    tail: AtomicUsize,
+}
+
+#[cfg(feature = "irq")]
+impl RxRing {
+    const fn new() -> Self {
+        Self {
+            buf: [const { AtomicU8::new(0) }; RX_BUF_SIZE],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    /// Pushes a byte, returns `false` if the buffer is full.
+    fn push(&self, c: u8) -> bool {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let head = self.head.load(Ordering::Acquire);
+        if tail.wrapping_sub(head) == RX_BUF_SIZE {
+            return false;
+        }
+        self.buf[tail % RX_BUF_SIZE].store(c, Ordering::Relaxed);
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        true
+    }
+
+    /// Pops the oldest byte, or returns [`None`] if the buffer is empty.
+    fn pop(&self) -> Option<u8> {
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+        if head == tail {
+            return None;
+        }
+        let c = self.buf[head % RX_BUF_SIZE].load(Ordering::Relaxed);
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        Some(c)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.head.load(Ordering::Acquire) == self.tail.load(Ordering::Acquire)
+    }
+}
+
+/// Moves all input pending in the console device into the ring buffer.
+#[cfg(feature = "irq")]
+fn fill_rx_buf() {
+    if !CONSOLE.is_inited() {
+        return;
+    }
+    loop {
+        // unlock before filtering, the sysrq actions print to the console
+        let Some(c) = CONSOLE.lock().getchar() else {
+            break;
+        };
+        match crate::sysrq::filter(c) {
+            // keep draining when the ring is full so that magic keys still
+            // get through, the data bytes are dropped
+            Filtered::Pass(c) => {
+                RX_BUF.push(c);
+            }
+            Filtered::Consumed => {}
+            Filtered::Run(action) => crate::sysrq::run_in_irq(action),
+        }
+    }
+}
+
+/// Polls the console device for a byte, handling magic-key sequences.
+#[cfg(not(feature = "irq"))]
+fn poll_char() -> Option<u8> {
+    loop {
+        let c = CONSOLE.lock().getchar()?;
+        match crate::sysrq::filter(c) {
+            Filtered::Pass(c) => return Some(c),
+            Filtered::Consumed => {}
+            Filtered::Run(action) => crate::sysrq::run(action),
+        }
+    }
+}
+
+/// Console receive interrupt handler.
+#[cfg(feature = "irq")]
+fn handle_console_irq() {
+    if CONSOLE.lock().ack_irq() {
+        fill_rx_buf();
+    }
+}
+
+/// Sets whether [`ConsoleIf::read_bytes`] blocks until at least one byte is
+/// available.
+///
+/// In blocking mode the caller waits on the IRQ notification instead of
+/// polling the console device.
+#[cfg(feature = "irq")]
+pub fn set_read_blocking(blocking: bool) {
+    RX_BLOCKING.store(blocking, Ordering::Release);
+}
+
 /// Reads a byte from the console, or returns [`None`] if no input is available.
 pub fn getchar() -> Option<u8> {
-    UART.lock().getchar()
+    if !CONSOLE.is_inited() {
+        return None;
+    }
+    #[cfg(feature = "irq")]
+    if RX_BUF.is_empty() {
+        fill_rx_buf();
+    }
+    crate::sysrq::run_pending();
+    #[cfg(feature = "irq")]
+    let c = RX_BUF.pop();
+    #[cfg(not(feature = "irq"))]
+    let c = poll_char();
+    c
+}
+
+/// Console input mode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InputMode {
+    /// Bytes are returned as soon as they arrive.
+    Raw,
+    /// Bytes are returned a line at a time, after line editing.
+    Canonical,
 }
 
-/// Early stage initialization of the PL011 UART driver.
-pub fn init_early(uart_base: VirtAddr) {
-    UART.init_once(SpinNoIrq::new(Pl011Uart::new(uart_base.as_mut_ptr())));
-    UART.lock().init();
+/// Control events recognised by the line discipline.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConsoleEvent {
+    /// Ctrl-C was typed, the pending line is discarded.
+    Interrupt,
+    /// Ctrl-D was typed on an empty line.
+    EndOfFile,
+}
+
+/// Line discipline settings for console input.
+#[derive(Clone, Copy, Debug)]
+pub struct LineDiscipline {
+    /// Raw or canonical input.
+    pub mode: InputMode,
+    /// Echo input back to the console.
+    pub echo: bool,
+    /// Map carriage return to line feed.
+    pub icrnl: bool,
+    /// Recognise the interrupt and end-of-file characters.
+    pub isig: bool,
+    /// Erases the last character of the line (canonical mode).
+    pub erase: u8,
+    /// Erases the whole line (canonical mode).
+    pub kill: u8,
+    /// Raises [`ConsoleEvent::Interrupt`].
+    pub intr: u8,
+    /// Raises [`ConsoleEvent::EndOfFile`] or flushes a non-empty line.
+    pub eof: u8,
+}
+
+impl Default for LineDiscipline {
+    fn default() -> Self {
+        Self {
+            mode: InputMode::Canonical,
+            echo: true,
+            icrnl: true,
+            isig: true,
+            erase: 0x7f,
+            kill: 0x15,
+            intr: 0x03,
+            eof: 0x04,
+        }
+    }
+}
+
+/// Maximum length of a line being edited in canonical mode.
+const MAX_LINE_LEN: usize = 256;
+
+/// Line discipline state, [`None`] settings means input is passed through as is.
+static LDISC: IrqLock<LineState> = IrqLock::new(LineState::new());
+
+/// Callbacks subscribed to [`ConsoleEvent`]s.
+static EVENT_HANDLERS: IrqLock<Vec<fn(ConsoleEvent)>> = IrqLock::new(Vec::new());
+
+struct LineState {
+    settings: Option<LineDiscipline>,
+    /// The line being edited.
+    line: Vec<u8>,
+    /// Bytes ready to be returned to readers.
+    ready: VecDeque<u8>,
+    /// An end-of-file is pending for the next reader.
+    eof: bool,
+}
+
+impl LineState {
+    const fn new() -> Self {
+        Self {
+            settings: None,
+            line: Vec::new(),
+            ready: VecDeque::new(),
+            eof: false,
+        }
+    }
+
+    /// Processes an input byte, returns the event it raised, if any.
+    fn input(&mut self, ld: &LineDiscipline, mut c: u8) -> Option<ConsoleEvent> {
+        if ld.icrnl && c == b'\r' {
+            c = b'\n';
+        }
+        if ld.isig && c == ld.intr {
+            self.line.clear();
+            if ld.echo {
+                echo(b"^C\n");
+            }
+            return Some(ConsoleEvent::Interrupt);
+        }
+        if ld.mode == InputMode::Raw {
+            if ld.isig && c == ld.eof {
+                self.eof = true;
+                return Some(ConsoleEvent::EndOfFile);
+            }
+            self.ready.push_back(c);
+            if ld.echo {
+                echo(&[c]);
+            }
+            return None;
+        }
+
+        if ld.isig && c == ld.eof {
+            if self.line.is_empty() {
+                self.eof = true;
+                return Some(ConsoleEvent::EndOfFile);
+            }
+            self.ready.extend(self.line.drain(..));
+        } else if c == ld.erase || c == b'\x08' {
+            if self.line.pop().is_some() && ld.echo {
+                echo(b"\x08 \x08");
+            }
+        } else if c == ld.kill {
+            while self.line.pop().is_some() {
+                if ld.echo {
+                    echo(b"\x08 \x08");
+                }
+            }
+        } else if c == b'\n' {
+            self.line.push(c);
+            self.ready.extend(self.line.drain(..));
+            if ld.echo {
+                echo(b"\n");
+            }
+        } else if self.line.len() < MAX_LINE_LEN - 1 {
+            self.line.push(c);
+            if ld.echo {
+                echo(&[c]);
+            }
+        }
+        None
+    }
+}
+
+/// Writes echoed input to the console backend.
+fn echo(bytes: &[u8]) {
+    if CONSOLE.is_inited() {
+        CONSOLE.lock().write_bytes(bytes);
+    }
+}
+
+/// Enables the line discipline with the given settings, or disables it with
+/// [`None`] so that input is passed through unchanged.
+///
+/// Pending input that has not been returned to a reader is discarded.
+pub fn set_line_discipline(settings: Option<LineDiscipline>) {
+    let mut state = LDISC.lock();
+    state.settings = settings;
+    state.line.clear();
+    state.ready.clear();
+    state.eof = false;
+}
+
+/// Returns the current line discipline settings.
+pub fn line_discipline() -> Option<LineDiscipline> {
+    LDISC.lock().settings
+}
+
+/// Subscribes a callback to [`ConsoleEvent`]s.
+///
+/// Callbacks run in the context of the reader that consumed the control
+/// character.
+pub fn subscribe(handler: fn(ConsoleEvent)) {
+    EVENT_HANDLERS.lock().push(handler);
+}
+
+fn notify_event(event: ConsoleEvent) {
+    let handlers = EVENT_HANDLERS.lock().clone();
+    for handler in handlers {
+        handler(event);
+    }
+}
+
+/// Reads input through the line discipline.
+///
+/// Returns [`None`] if no input is available yet, `Some(0)` on end-of-file.
+fn ldisc_read(ld: &LineDiscipline, bytes: &mut [u8]) -> Option<usize> {
+    let mut events = Vec::new();
+    let mut state = LDISC.lock();
+    while state.ready.is_empty() && !state.eof {
+        let Some(c) = getchar() else {
+            break;
+        };
+        if let Some(event) = state.input(ld, c) {
+            events.push(event);
+        }
+    }
+
+    let res = if !state.ready.is_empty() {
+        let n = bytes.len().min(state.ready.len());
+        for (dst, src) in bytes.iter_mut().zip(state.ready.drain(..n)) {
+            *dst = src;
+        }
+        Some(n)
+    } else if state.eof {
+        state.eof = false;
+        Some(0)
+    } else {
+        None
+    };
+    drop(state);
+
+    for event in events {
+        notify_event(event);
+    }
+    res
+}
+
+/// Reads the input available right now, through the line discipline if enabled.
+///
+/// Returns [`None`] if no input is available yet, `Some(0)` on end-of-file.
+fn read_available(bytes: &mut [u8]) -> Option<usize> {
+    if let Some(ld) = line_discipline() {
+        return ldisc_read(&ld, bytes);
+    }
+
+    let mut read_len = 0;
+    while read_len < bytes.len() {
+        if let Some(c) = getchar() {
+            bytes[read_len] = c;
+        } else {
+            break;
+        }
+        read_len += 1;
+    }
+    (read_len > 0).then_some(read_len)
+}
+
+/// Returns whether readers wait for input, see [`set_read_blocking`].
+fn read_blocking() -> bool {
+    #[cfg(feature = "irq")]
+    let blocking = RX_BLOCKING.load(Ordering::Acquire);
+    #[cfg(not(feature = "irq"))]
+    let blocking = false;
+    blocking
+}
+
+/// Waits until the console device may have new input.
+#[cfg(feature = "irq")]
+fn wait_for_input() {
+    fill_rx_buf();
+    if RX_BUF.is_empty() && crate::irq::wait_and_handle_irq().is_err() {
+        sel4::r#yield();
+    }
+}
+
+/// Returns the type of the UART the console backend drives, or [`None`] if it
+/// is not a UART.
+#[cfg(not(feature = "uart-ipc"))]
+fn console_uart_type() -> Option<&'static str> {
+    match CONSOLE_BACKEND {
+        "uart" => Some(UART_TYPE),
+        _ => None,
+    }
+}
+
+/// Creates the console backend selected by the configuration.
+#[cfg(not(feature = "uart-ipc"))]
+fn create_backend() -> InitResult<Box<dyn ConsoleBackend>> {
+    if let Some(uart_type) = console_uart_type() {
+        return Ok(match uart_type {
+            "pl011" => Box::new(Pl011Backend::new()?),
+            "ns16550" => Box::new(Ns16550Backend::new()?),
+            _ => return Err(PlatformInitError::DeviceNotFound(uart_type)),
+        });
+    }
+    Ok(match CONSOLE_BACKEND {
+        #[cfg(feature = "sel4-debug")]
+        "sel4-debug" => Box::new(Sel4DebugBackend),
+        "virtio" => Box::new(
+            virtio::VirtioBackend::probe()
+                .ok_or(PlatformInitError::DeviceNotFound("virtio-console"))?,
+        ),
+        _ => return Err(PlatformInitError::DeviceNotFound(CONSOLE_BACKEND)),
+    })
+}
+
+/// Creates the uart-thread IPC backend, the UART is owned by the server.
+///
+/// It fails if the root task passed no uart-thread endpoint.
+#[cfg(feature = "uart-ipc")]
+fn create_backend() -> InitResult<Box<dyn ConsoleBackend>> {
+    if crate::bootinfo::boot_info().uart_ep == 0 {
+        return Err(PlatformInitError::DeviceNotFound("uart-thread"));
+    }
+    Ok(Box::new(UartIpcBackend))
+}
+
+/// Early stage initialization of the console backend.
+///
+/// Output buffered by the early console is replayed through the backend.
+/// On failure, output keeps going to the early console.
+pub fn init_early() -> InitResult {
+    #[allow(unused_mut)]
+    let mut backend = create_backend()?;
+    // Replay before publishing the backend, writers racing with the replay
+    // wait for the early buffer and then see the backend.
+    #[cfg(not(feature = "sel4-debug"))]
+    let mut early = EARLY_BUF.lock();
+    #[cfg(not(feature = "sel4-debug"))]
+    {
+        backend.write_bytes(&early.buf[..early.len]);
+        if early.truncated {
+            backend.write_bytes(b"[early console output truncated]\n");
+        }
+        early.len = 0;
+        early.truncated = false;
+    }
+    CONSOLE.init_once(IrqLock::new(backend));
+    Ok(())
+}
+
+/// Later stage initialization: registers the console receive interrupt.
+///
+/// If another handler already owns the IRQ, the console keeps polling.
+#[cfg(feature = "irq")]
+pub(crate) fn init_later() -> InitResult {
+    let Some(irq) = CONSOLE.lock().irq_num() else {
+        return Ok(());
+    };
+    if !crate::irq::register(irq, handle_console_irq)? {
+        log::warn!("Console IRQ {} is already taken, falling back to polling", irq);
+    }
+    Ok(())
+}
+
+/// Writes bytes to the console without keeping them in the kernel log.
+pub(crate) fn write_unlogged(bytes: &[u8]) {
+    if CONSOLE.is_inited() {
+        CONSOLE.lock().write_bytes(bytes);
+    } else {
+        early_write_bytes(bytes);
+    }
 }
 
 struct ConsoleIfImpl;
@@ -35,25 +935,26 @@ struct ConsoleIfImpl;
 impl ConsoleIf for ConsoleIfImpl {
     /// Writes given bytes to the console.
     fn write_bytes(bytes: &[u8]) {
-        let mut uart = UART.lock();
-        for c in bytes {
-            do_putchar(&mut uart, *c);
-        }
+        crate::dmesg::write(bytes);
+        write_unlogged(bytes);
     }
 
     /// Reads bytes from the console into the given mutable slice.
     ///
     /// Returns the number of bytes read.
     fn read_bytes(bytes: &mut [u8]) -> usize {
-        let mut read_len = 0;
-        while read_len < bytes.len() {
-            if let Some(c) = getchar() {
-                bytes[read_len] = c;
-            } else {
-                break;
+        if bytes.is_empty() {
+            return 0;
+        }
+        loop {
+            if let Some(n) = read_available(bytes) {
+                return n;
+            }
+            if !read_blocking() {
+                return 0;
             }
-            read_len += 1;
+            #[cfg(feature = "irq")]
+            wait_for_input();
         }
-        read_len
     }
 }
@@ -0,0 +1,92 @@
+//! In-memory kernel log ring.
+//!
+//! Everything written through [`ConsoleIf::write_bytes`] is also kept here, so
+//! boot messages can be read back when nobody was watching the console. When
+//! the ring is full, the oldest bytes are overwritten.
+//!
+//! A parent component reads the log of a child with [`fetch_log`], which the
+//! child answers by passing the request to [`serve_request`].
+//!
+//! [`ConsoleIf::write_bytes`]: axplat::console::ConsoleIf::write_bytes
+//! [`fetch_log`]: crate::ipc::fetch_log
+//! [`serve_request`]: crate::ipc::serve_request
+
+use alloc::vec::Vec;
+
+use crate::utils::lock::IrqLock;
+
+/// Capacity of the log ring in bytes.
+pub const DMESG_SIZE: usize = 0x4000;
+
+static DMESG: IrqLock<LogRing> = IrqLock::new(LogRing::new());
+
+struct LogRing {
+    buf: [u8; DMESG_SIZE],
+    /// Total number of bytes ever written, the write position is `total % DMESG_SIZE`.
+    total: usize,
+    /// Number of bytes currently retained.
+    len: usize,
+}
+
+impl LogRing {
+    const fn new() -> Self {
+        Self {
+            buf: [0; DMESG_SIZE],
+            total: 0,
+            len: 0,
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        // only the tail of an oversized write can be retained
+        let bytes = &bytes[bytes.len().saturating_sub(DMESG_SIZE)..];
+        let pos = self.total % DMESG_SIZE;
+        let first = bytes.len().min(DMESG_SIZE - pos);
+        self.buf[pos..pos + first].copy_from_slice(&bytes[..first]);
+        self.buf[..bytes.len() - first].copy_from_slice(&bytes[first..]);
+        self.total += bytes.len();
+        self.len = (self.len + bytes.len()).min(DMESG_SIZE);
+    }
+
+    fn read(&self, offset: usize, out: &mut [u8]) -> usize {
+        if offset >= self.len {
+            return 0;
+        }
+        let n = out.len().min(self.len - offset);
+        let start = (self.total - self.len + offset) % DMESG_SIZE;
+        let first = n.min(DMESG_SIZE - start);
+        out[..first].copy_from_slice(&self.buf[start..start + first]);
+        out[first..n].copy_from_slice(&self.buf[..n - first]);
+        n
+    }
+}
+
+/// Appends bytes to the log ring.
+pub(crate) fn write(bytes: &[u8]) {
+    DMESG.lock().write(bytes);
+}
+
+/// Reads retained log bytes starting at `offset` from the oldest one.
+///
+/// Returns the number of bytes read.
+pub fn read(offset: usize, buf: &mut [u8]) -> usize {
+    DMESG.lock().read(offset, buf)
+}
+
+/// Returns a copy of all retained log bytes, oldest first.
+pub fn snapshot() -> Vec<u8> {
+    let ring = DMESG.lock();
+    let mut buf = alloc::vec![0; ring.len];
+    ring.read(0, &mut buf);
+    buf
+}
+
+/// Discards all retained log bytes.
+pub fn clear() {
+    DMESG.lock().len = 0;
+}
+
+/// Returns the number of retained log bytes.
+pub fn len() -> usize {
+    DMESG.lock().len
+}
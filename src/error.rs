@@ -0,0 +1,77 @@
+//! Errors of the platform initialization.
+
+use core::fmt;
+
+/// Error raised by a platform initialization step.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlatformInitError {
+    /// No free slots were handed over to the component.
+    SlotExhausted,
+    /// Untyped memory ran out while retyping kernel objects or frames.
+    UntypedExhausted(sel4::Error),
+    /// Mapping the page at virtual address, or the device at physical address,
+    /// `addr` failed.
+    MapFailed { addr: usize, err: sel4::Error },
+    /// Obtaining an IRQ handler or binding its notification failed.
+    IrqBindFailed(sel4::Error),
+    /// The configured device was not found.
+    DeviceNotFound(&'static str),
+    /// A configuration value is not supported.
+    InvalidConfig(&'static str),
+    /// The boot information passed by the root task has an unknown magic
+    /// number or version, or is malformed.
+    BadBootInfo { magic: u64, version: u32 },
+    /// Creating or starting the thread of secondary CPU `cpu_id` failed.
+    CpuBootFailed { cpu_id: usize, err: sel4::Error },
+    /// Configuring a thread of the platform failed.
+    ThreadSetupFailed(sel4::Error),
+}
+
+impl PlatformInitError {
+    /// Classifies a seL4 error raised while mapping `addr`.
+    pub(crate) fn map_failed(addr: usize, err: sel4::Error) -> Self {
+        match err {
+            sel4::Error::NotEnoughMemory => Self::UntypedExhausted(err),
+            err => Self::MapFailed { addr, err },
+        }
+    }
+
+    /// Returns the seL4 error that caused the failure, if any.
+    pub fn sel4_error(&self) -> Option<sel4::Error> {
+        match *self {
+            Self::UntypedExhausted(err)
+            | Self::IrqBindFailed(err)
+            | Self::ThreadSetupFailed(err) => Some(err),
+            Self::MapFailed { err, .. } | Self::CpuBootFailed { err, .. } => Some(err),
+            Self::SlotExhausted
+            | Self::DeviceNotFound(_)
+            | Self::InvalidConfig(_)
+            | Self::BadBootInfo { .. } => None,
+        }
+    }
+}
+
+impl fmt::Display for PlatformInitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::SlotExhausted => write!(f, "no free slots"),
+            Self::UntypedExhausted(err) => write!(f, "untyped memory exhausted ({:?})", err),
+            Self::MapFailed { addr, err } => write!(f, "failed to map {:#x} ({:?})", addr, err),
+            Self::IrqBindFailed(err) => write!(f, "failed to bind IRQ notification ({:?})", err),
+            Self::DeviceNotFound(dev) => write!(f, "device not found: {}", dev),
+            Self::InvalidConfig(msg) => write!(f, "invalid config: {}", msg),
+            Self::BadBootInfo { magic, version } => write!(
+                f,
+                "unrecognised boot information (magic {:#x}, version {})",
+                magic, version
+            ),
+            Self::CpuBootFailed { cpu_id, err } => {
+                write!(f, "failed to boot CPU {} ({:?})", cpu_id, err)
+            }
+            Self::ThreadSetupFailed(err) => write!(f, "failed to set up a thread ({:?})", err),
+        }
+    }
+}
+
+/// Result of a platform initialization step.
+pub type InitResult<T = ()> = Result<T, PlatformInitError>;
@@ -1,7 +1,114 @@
+//! Platform initialization.
+//!
+//! Each initialization stage is timestamped with `CNTPCT_EL0`, the timings are
+//! printed at the end of [`InitIf::init_later`] and available from
+//! [`boot_stages`].
+
+use alloc::vec::Vec;
 use axplat::init::InitIf;
+use axplat::power::PowerIf;
+use kspin::SpinNoIrq;
+
+use crate::error::{InitResult, PlatformInitError};
+use crate::time::{current_ticks, ticks_to_nanos};
+
+/// Platform initialization stages.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InitStage {
+    IpcBuffer,
+    BootInfo,
+    Slots,
+    Console,
+    Time,
+    ObjAllocator,
+    Memory,
+    Irq,
+    Rtc,
+    SecondaryCpu,
+}
+
+impl InitStage {
+    const COUNT: usize = 10;
+}
+
+/// Timing of a completed initialization stage.
+#[derive(Clone, Copy, Debug)]
+pub struct StageTiming {
+    pub stage: InitStage,
+    /// `CNTPCT_EL0` value when the stage started.
+    pub start_ticks: u64,
+    /// `CNTPCT_EL0` value when the stage finished.
+    pub end_ticks: u64,
+}
+
+impl StageTiming {
+    /// Returns the duration of the stage in nanoseconds.
+    pub fn duration_nanos(&self) -> u64 {
+        ticks_to_nanos(self.end_ticks - self.start_ticks)
+    }
+}
 
-use crate::config::devices::UART_PADDR;
-use axplat::mem::va;
+/// `CNTPCT_EL0` value when [`InitIf::init_early`] was entered.
+static BOOT_START_TICKS: SpinNoIrq<u64> = SpinNoIrq::new(0);
+
+/// Timings of the completed stages, indexed by [`InitStage`].
+static STAGE_TIMINGS: SpinNoIrq<[Option<StageTiming>; InitStage::COUNT]> =
+    SpinNoIrq::new([None; InitStage::COUNT]);
+
+/// Runs an initialization stage and records its timing, or reports its failure.
+fn run_stage<T>(stage: InitStage, f: impl FnOnce() -> InitResult<T>) -> T {
+    let start_ticks = current_ticks();
+    let res = f();
+    let end_ticks = current_ticks();
+    STAGE_TIMINGS.lock()[stage as usize] = Some(StageTiming {
+        stage,
+        start_ticks,
+        end_ticks,
+    });
+    res.unwrap_or_else(|err| report_init_failure(stage, err))
+}
+
+/// Returns the timings of the completed initialization stages, in the order
+/// they ran.
+pub fn boot_stages() -> Vec<StageTiming> {
+    let mut stages: Vec<_> = STAGE_TIMINGS.lock().iter().flatten().copied().collect();
+    stages.sort_by_key(|t| t.start_ticks);
+    stages
+}
+
+/// Prints the timings of the completed initialization stages.
+pub fn print_boot_report() {
+    let boot_start = *BOOT_START_TICKS.lock();
+    axplat::console_println!("{:<14} {:>12} {:>12}", "boot stage", "start (us)", "time (us)");
+    let mut end = boot_start;
+    for t in boot_stages() {
+        axplat::console_println!(
+            "{:<14} {:>12} {:>12}",
+            alloc::format!("{:?}", t.stage),
+            ticks_to_nanos(t.start_ticks - boot_start) / 1000,
+            t.duration_nanos() / 1000
+        );
+        end = end.max(t.end_ticks);
+    }
+    axplat::console_println!(
+        "{:<14} {:>12} {:>12}",
+        "total",
+        "",
+        ticks_to_nanos(end - boot_start) / 1000
+    );
+}
+
+/// Prints the failing stage and its seL4 error, then powers the system off.
+///
+/// Output goes to the early console if the console backend is not ready, it
+/// is flushed to the UART when powering off, if the UART can be mapped.
+pub(crate) fn report_init_failure(stage: InitStage, err: PlatformInitError) -> ! {
+    axplat::console_println!("platform init failed at stage {:?}: {}", stage, err);
+    if let Some(sel4_err) = err.sel4_error() {
+        axplat::console_println!("  seL4 error: {:?}", sel4_err);
+    }
+    crate::power::PowerImpl::system_off()
+}
 
 struct InitIfImpl;
 
@@ -17,8 +124,8 @@ impl InitIf for InitIfImpl {
     ///
     /// * `cpu_id` is the logical CPU ID (0, 1, ..., N-1, N is the number of CPU
     /// cores on the platform).
-    /// * `arg` is passed from the bootloader (typically the device tree blob
-    /// address).
+    /// * `arg` is the address of the [`BootInfo`](crate::bootinfo::BootInfo)
+    /// placed by the root task, or 0 to use the built-in layout.
     ///
     /// # Before calling this function
     ///
@@ -31,14 +138,25 @@ impl InitIf for InitIfImpl {
     /// * Exception & interrupt handlers are set up.
     /// * Early console is initialized.
     /// * Current monotonic time and wall time can be obtained.
-    fn init_early(_cpu_id: usize, _arg: usize) {
-        sel4_kit::ipc_buffer::init_ipc_buffer();
-        common::slot::init(common::config::DEFAULT_EMPTY_SLOT_INDEX..0x1000);
-        common::slot::init_recv_slot();
-        crate::console::init_early(va!(UART_PADDR));
-        crate::time::init_early();
-        crate::utils::obj::init();
-        crate::mem::init();
+    fn init_early(_cpu_id: usize, arg: usize) {
+        *BOOT_START_TICKS.lock() = current_ticks();
+        run_stage(InitStage::IpcBuffer, || {
+            sel4_kit::ipc_buffer::init_ipc_buffer();
+            Ok(())
+        });
+        // after the IPC buffer, reporting a failure powers off through the root task
+        run_stage(InitStage::BootInfo, || crate::bootinfo::init(arg));
+        run_stage(InitStage::Slots, crate::utils::obj::init_slots);
+        run_stage(InitStage::Time, || {
+            crate::time::init_early();
+            Ok(())
+        });
+        run_stage(InitStage::ObjAllocator, || {
+            crate::utils::obj::init();
+            Ok(())
+        });
+        run_stage(InitStage::Memory, crate::mem::init);
+        run_stage(InitStage::Console, crate::console::init_early);
         #[cfg(feature = "irq")]
         crate::irq::init_early();
     }
@@ -47,7 +165,9 @@ impl InitIf for InitIfImpl {
     ///
     /// See [`init_early`] for details.
     #[cfg(feature = "smp")]
-    fn init_early_secondary(cpu_id: usize) {}
+    fn init_early_secondary(cpu_id: usize) {
+        crate::smp::init_early_secondary(cpu_id);
+    }
 
     /// Initializes the platform at the later stage for the primary core.
     ///
@@ -76,14 +196,18 @@ impl InitIf for InitIfImpl {
     fn init_later(_cpu_id: usize, _arg: usize) {
 
         #[cfg(feature = "irq")]
-        crate::irq::init_later();
+        run_stage(InitStage::Irq, || {
+            crate::irq::init_later()?;
+            crate::console::init_later()
+        });
+        run_stage(InitStage::Rtc, crate::time::init_later);
+
+        print_boot_report();
     }
 
     /// Initializes the platform at the later stage for secondary cores.
     ///
     /// See [`init_later`] for details.
     #[cfg(feature = "smp")]
-    fn init_later_secondary(cpu_id: usize) {
-        todo!()
-    }
+    fn init_later_secondary(_cpu_id: usize) {}
 }
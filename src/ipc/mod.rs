@@ -1,5 +1,10 @@
+#[cfg(feature = "uart-ipc")]
+pub mod uart;
+
 use common_macros::generate_ipc_send;
 use num_enum::{IntoPrimitive, TryFromPrimitive};
+use sel4::{MessageInfo, MessageInfoBuilder, cap::Endpoint};
+use sel4_kit::slot_manager::LeafSlot;
 
 #[derive(Debug, IntoPrimitive, TryFromPrimitive)]
 #[repr(u64)]
@@ -8,8 +13,19 @@ pub enum ServiceEvent {
     SwitchTask,
     ExitTask,
     ExitSystem,
+    FetchLog,
+    RegisterIrq,
 }
 
+const WORD_SIZE: usize = core::mem::size_of::<sel4::Word>();
+
+/// Target core word of a [`ServiceEvent::RegisterIrq`] request that leaves the
+/// routing of the IRQ to the kernel.
+pub const IRQ_CORE_ANY: usize = usize::MAX;
+
+/// Maximum number of log bytes carried by a single [`ServiceEvent::FetchLog`] reply.
+pub const FETCH_LOG_CHUNK_SIZE: usize = 0x200;
+
 macro_rules! call_ep {
     ($msg:expr) => {
         common::config::DEFAULT_PARENT_EP.call($msg)
@@ -27,3 +43,74 @@ pub fn exit_task(task: usize) -> usize {}
 
 #[generate_ipc_send(label = ServiceEvent::ExitSystem)]
 pub fn exit_system() -> usize {}
+
+/// Asks the root task for the IRQ handler of `irq` with the given trigger mode
+/// and target core ([`IRQ_CORE_ANY`] for none), and places it in `slot`.
+pub fn register_irq(
+    irq: usize,
+    edge_triggered: bool,
+    core: usize,
+    slot: LeafSlot,
+) -> sel4::Result<()> {
+    sel4::with_ipc_buffer_mut(|ib| {
+        ib.set_recv_slot(&slot.abs_cptr());
+        ib.msg_regs_mut()[..3].copy_from_slice(&[irq as _, edge_triggered as _, core as _]);
+    });
+    let msg = MessageInfoBuilder::default()
+        .label(ServiceEvent::RegisterIrq.into())
+        .length(3)
+        .build();
+    let reply = call_ep!(msg);
+    common::slot::init_recv_slot();
+    match reply.extra_caps() {
+        0 => Err(sel4::Error::FailedLookup),
+        _ => Ok(()),
+    }
+}
+
+/// Fetches the log of the child component served at `ep`.
+///
+/// Reads up to [`FETCH_LOG_CHUNK_SIZE`] bytes starting at `offset` from the
+/// oldest retained byte, returns the number of bytes read.
+pub fn fetch_log(ep: Endpoint, offset: usize, buf: &mut [u8]) -> usize {
+    sel4::with_ipc_buffer_mut(|ib| ib.msg_regs_mut()[0] = offset as _);
+    let msg = MessageInfoBuilder::default()
+        .label(ServiceEvent::FetchLog.into())
+        .length(1)
+        .build();
+    ep.call(msg);
+    sel4::with_ipc_buffer(|ib| {
+        let n = (ib.msg_regs()[0] as usize).min(buf.len());
+        buf[..n].copy_from_slice(&ib.msg_bytes()[WORD_SIZE..WORD_SIZE + n]);
+        n
+    })
+}
+
+/// Builds the reply to a [`ServiceEvent`] request served by the platform
+/// itself, or returns [`None`] if the request is left to the caller.
+///
+/// The platform runs no IPC server loop of its own. A component that receives
+/// requests on its endpoint, e.g. to let its parent fetch its log, must pass
+/// each received message here with the request still in the IPC buffer, and
+/// send the returned message as the reply. Only [`ServiceEvent::FetchLog`] is
+/// served for now.
+pub fn serve_request(msg: &MessageInfo) -> Option<MessageInfo> {
+    match ServiceEvent::try_from(msg.label()) {
+        Ok(ServiceEvent::FetchLog) => Some(reply_fetch_log()),
+        _ => None,
+    }
+}
+
+/// Builds the reply to a [`ServiceEvent::FetchLog`] request with this
+/// component's log, see [`serve_request`].
+pub fn reply_fetch_log() -> MessageInfo {
+    sel4::with_ipc_buffer_mut(|ib| {
+        let offset = ib.msg_regs()[0] as usize;
+        let bytes = &mut ib.msg_bytes_mut()[WORD_SIZE..WORD_SIZE + FETCH_LOG_CHUNK_SIZE];
+        let n = crate::dmesg::read(offset, bytes);
+        ib.msg_regs_mut()[0] = n as _;
+        MessageInfoBuilder::default()
+            .length(1 + n.div_ceil(WORD_SIZE))
+            .build()
+    })
+}
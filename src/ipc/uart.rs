@@ -0,0 +1,51 @@
+//! Client side of the uart-thread IPC protocol.
+//!
+//! Bytes are packed into the message registers after a leading length word.
+//! The message labels are the ones the server dispatches on.
+use sel4::{MessageInfoBuilder, cap::Endpoint};
+
+pub use uart_thread::UartEvent;
+
+use super::WORD_SIZE;
+use crate::bootinfo::boot_info;
+
+/// Maximum number of bytes carried by a single message.
+const MAX_CHUNK_SIZE: usize = 0x200;
+
+/// Endpoint of the uart-thread server, installed by the root task.
+fn uart_ep() -> Endpoint {
+    Endpoint::from_bits(boot_info().uart_ep)
+}
+
+/// Sends the given bytes to the uart-thread server.
+pub fn write_bytes(bytes: &[u8]) {
+    for chunk in bytes.chunks(MAX_CHUNK_SIZE) {
+        sel4::with_ipc_buffer_mut(|ib| {
+            ib.msg_regs_mut()[0] = chunk.len() as _;
+            ib.msg_bytes_mut()[WORD_SIZE..WORD_SIZE + chunk.len()].copy_from_slice(chunk);
+        });
+        let msg = MessageInfoBuilder::default()
+            .label(UartEvent::Write.into())
+            .length(1 + chunk.len().div_ceil(WORD_SIZE))
+            .build();
+        uart_ep().call(msg);
+    }
+}
+
+/// Receives pending input from the uart-thread server without blocking.
+///
+/// Returns the number of bytes read.
+pub fn read_bytes(bytes: &mut [u8]) -> usize {
+    let len = bytes.len().min(MAX_CHUNK_SIZE);
+    sel4::with_ipc_buffer_mut(|ib| ib.msg_regs_mut()[0] = len as _);
+    let msg = MessageInfoBuilder::default()
+        .label(UartEvent::Read.into())
+        .length(1)
+        .build();
+    uart_ep().call(msg);
+    sel4::with_ipc_buffer(|ib| {
+        let n = (ib.msg_regs()[0] as usize).min(len);
+        bytes[..n].copy_from_slice(&ib.msg_bytes()[WORD_SIZE..WORD_SIZE + n]);
+        n
+    })
+}
@@ -1,24 +1,82 @@
 //! This module provides the implementation of the IRQ interface for the seL4 platform.
 //! It initializes the IRQ handler, registers IRQs, and provides methods to enable/disable
+//!
+//! Every registered IRQ is assigned a distinct bit in the badge of a
+//! notification bank. seL4 ORs the badges of all signals delivered before the
+//! waiting thread wakes up, so the dispatcher walks the set bits and handles
+//! and acknowledges each IRQ. A bank holds 64 IRQs, further banks are created
+//! when more IRQs are in use. Only the first bank is bound to the primary
+//! thread. Each further bank is waited on by a relay thread, which records the
+//! badge and sets the summary bit of the first bank, so that the IRQs of every
+//! bank are handled wherever the first bank is.
+//!
+//! The global notification is served according to the `dispatch-mode` config:
+//! it is bound to the main thread, which handles IRQs when it waits on it
+//! ("yield" and "bound", the latter also blocking idle CPUs on it), or to a
+//! high-priority dispatcher thread that runs the handlers ("dispatcher").
+//!
+//! `disable_irqs` and `enable_irqs` set a per-CPU flag, `local_irq_save` and
+//! `local_irq_restore` nest. IRQs delivered to a CPU while they are disabled
+//! are left unacknowledged and recorded in a per-CPU pending bitmap, they are
+//! handled in IRQ number order once IRQs are enabled again. IRQs stay
+//! disabled while a handler runs.
 use axplat::irq::{HandlerTable, IrqHandler, IrqIf, IpiTarget};
 use kspin::SpinNoIrq;
 use lazyinit::LazyInit;
 
 // sel4 crates
+use alloc::boxed::Box;
 use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::alloc::Layout;
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use kspin::SpinNoIrqGuard;
 
-use common::{root::register_irq, slot::alloc_slot};
-use sel4::cap::{IrqHandler as Sel4IrqHandler, Notification};
+use common::root::register_irq;
+use sel4::cap::{IrqControl, IrqHandler as Sel4IrqHandler, Notification};
 use sel4_kit::slot_manager::LeafSlot;
 
-use crate::utils::obj::OBJ_ALLOCATOR;
+use crate::bootinfo::boot_info;
+use crate::config::devices::{IRQ_CONFIG, TIMER_IRQ};
+use crate::config::irq::{DISPATCH_MODE, DISPATCHER_PRIORITY, DISPATCHER_STACK_SIZE};
+use crate::error::{InitResult, PlatformInitError};
+use crate::time::{current_ticks, ticks_to_nanos};
+use crate::utils::obj::{alloc_slot, recycle_slot, retype};
+use crate::utils::thread::LocalThread;
 
 const MAX_IRQ_COUNT: usize = 1024;
 
+/// Number of IRQs that fit in the badge of one notification bank.
+const IRQS_PER_BANK: usize = usize::BITS as usize;
+
+/// Number of CPUs with their own interrupt state.
+const MAX_CPUS: usize = crate::config::plat::CPU_NUM;
+
+/// Number of words in a per-CPU pending bitmap.
+const PENDING_WORDS: usize = MAX_IRQ_COUNT.div_ceil(u64::BITS as usize);
+
+/// IRQs below this number are software generated (GIC SGIs) and used as IPIs,
+/// they get a badge bit in the first bank but no seL4 IRQ handler.
+const SGI_COUNT: usize = 16;
+
+/// Badge bit of the first bank signalled by the relay threads of the others.
+const SUMMARY_BIT: usize = IRQS_PER_BANK - 1;
+
+/// Stack size of a bank relay thread.
+const RELAY_STACK_SIZE: usize = 0x2000;
+
 static IRQ_HANDLER_TABLE: HandlerTable<MAX_IRQ_COUNT> = HandlerTable::new();
 
 static IRQ_CAPS: LazyInit<SpinNoIrq<IrqCap>> = LazyInit::new();
 
+/// Badge bits of the first bank received by the dispatcher thread but not
+/// handled yet, because [`IRQ_CAPS`] was held when they arrived.
+static DISPATCHER_SPILLED: AtomicUsize = AtomicUsize::new(0);
+
+static CPU_IRQ_STATES: [CpuIrqState; MAX_CPUS] = [const { CpuIrqState::new() }; MAX_CPUS];
+
 #[allow(unused_macros)]
 macro_rules! handle_trap {
     ($trap:ident, $($args:tt)*) => {{
@@ -39,37 +97,622 @@ pub(crate) fn init_early() {
     IRQ_CAPS.init_once(SpinNoIrq::new(IrqCap::new()));
 }
 
-pub(crate) fn init_later() {
-    IRQ_CAPS.lock().init().unwrap();
+/// Later stage initialization: creates the global notification, binds the
+/// timer IRQ and starts the dispatcher thread if configured.
+///
+/// Without the timer IRQ, for example because the root task does not hand
+/// out its IRQ, the platform keeps running but one-shot timers never fire.
+pub(crate) fn init_later() -> InitResult {
+    let mode = DispatchMode::from_config()?;
+    let notify = irq_caps().init()?;
+    // outside of `irq_caps`, which restores the state on release
+    CPU_IRQ_STATES[0].disabled.store(false, Ordering::Release);
+    // deliver the expiry of the one-shot timer through the IRQ path
+    if let Err(err) = irq_caps().register_sel4_irq(TIMER_IRQ) {
+        log::error!(
+            "Failed to bind timer IRQ {}, one-shot timers never fire: {}",
+            TIMER_IRQ,
+            err
+        );
+    }
+    if mode == DispatchMode::Dispatcher {
+        spawn_dispatcher(notify)?;
+    } else {
+        log::warn!("IRQs are only handled while the primary CPU waits, no preemption");
+    }
+    Ok(())
+}
+
+/// How the global IRQ notification is served, see the `dispatch-mode` config.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum DispatchMode {
+    /// Bound to the main thread, idle CPUs yield.
+    Yield,
+    /// Bound to the main thread, idle CPUs block on their notification.
+    Bound,
+    /// Served by a dedicated dispatcher thread.
+    Dispatcher,
+}
+
+impl DispatchMode {
+    /// Parses the `dispatch-mode` config.
+    fn from_config() -> InitResult<Self> {
+        match DISPATCH_MODE {
+            "yield" => Ok(Self::Yield),
+            "bound" => Ok(Self::Bound),
+            "dispatcher" => Ok(Self::Dispatcher),
+            _ => Err(PlatformInitError::InvalidConfig("unknown dispatch-mode")),
+        }
+    }
+}
+
+fn dispatch_mode() -> DispatchMode {
+    // an unknown mode is rejected by `init_later`
+    DispatchMode::from_config().unwrap_or(DispatchMode::Yield)
+}
+
+/// Locks [`IRQ_CAPS`] with IRQs disabled on the current CPU.
+///
+/// IRQs are disabled so that the IRQ locks taken while it is held, e.g. by
+/// logging, do not replay deferred IRQs on release. On release it restores the
+/// IRQ state, which replays the IRQs deferred meanwhile, and wakes the
+/// dispatcher thread up if it received IRQs while the lock was held.
+fn irq_caps() -> IrqCapsGuard {
+    let flags = local_irq_save();
+    IrqCapsGuard {
+        guard: ManuallyDrop::new(IRQ_CAPS.lock()),
+        flags,
+    }
+}
+
+struct IrqCapsGuard {
+    guard: ManuallyDrop<SpinNoIrqGuard<'static, IrqCap>>,
+    flags: usize,
+}
+
+impl Deref for IrqCapsGuard {
+    type Target = IrqCap;
+
+    fn deref(&self) -> &IrqCap {
+        &self.guard
+    }
+}
+
+impl DerefMut for IrqCapsGuard {
+    fn deref_mut(&mut self) -> &mut IrqCap {
+        &mut self.guard
+    }
+}
+
+impl Drop for IrqCapsGuard {
+    fn drop(&mut self) {
+        let notify = self.guard.global_notify;
+        // unlock first, the replayed IRQs take the lock again
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        local_irq_restore(self.flags);
+        if DISPATCHER_SPILLED.load(Ordering::Acquire) != 0 {
+            notify.signal();
+        }
+    }
+}
+
+/// Creates the dispatcher thread, bound to the global notification.
+fn spawn_dispatcher(notify: Notification) -> InitResult {
+    let thread = LocalThread::new(DISPATCHER_PRIORITY)?;
+    let start = || -> sel4::Result<()> {
+        // share the core of the primary CPU so that it never runs concurrently with it
+        #[cfg(feature = "smp")]
+        thread.tcb.tcb_set_affinity(0)?;
+        thread.tcb.tcb_bind_notification(notify)?;
+
+        let stack_top = alloc_stack(DISPATCHER_STACK_SIZE)?;
+        thread.start(dispatcher_entry, stack_top, thread.ipc_buffer_addr)
+    };
+    start().map_err(PlatformInitError::IrqBindFailed)
+}
+
+/// Allocates a notification, see [`retype`].
+fn alloc_notification() -> InitResult<Notification> {
+    retype(sel4::cap::Untyped::from_bits(boot_info().obj_untyped))
+}
+
+/// Allocates the stack of a platform thread, returns its top.
+fn alloc_stack(size: usize) -> sel4::Result<usize> {
+    let layout = Layout::from_size_align(size, 16).unwrap();
+    let stack = unsafe { alloc::alloc::alloc(layout) };
+    if stack.is_null() {
+        return Err(sel4::Error::NotEnoughMemory);
+    }
+    Ok(stack as usize + size)
+}
+
+/// Entry of the dispatcher thread, runs the handlers of the IRQs delivered on
+/// the global notification on behalf of the primary CPU.
+extern "C" fn dispatcher_entry(ipc_buffer_addr: usize) -> ! {
+    sel4::set_ipc_buffer(unsafe { &mut *(ipc_buffer_addr as *mut sel4::IpcBuffer) });
+    let notify = IRQ_CAPS.lock().global_notify;
+    loop {
+        let badge = notify.wait();
+        DISPATCHER_SPILLED.fetch_or(badge as _, Ordering::AcqRel);
+        // The dispatcher has a higher priority than the primary CPU thread on
+        // the same core, so spinning on a lock that thread holds never ends.
+        // Leave the IRQs spilled instead, the holder signals on release. The
+        // other locks shared with handlers are `IrqLock`s, while the primary
+        // CPU holds one its IRQs are disabled and `handle_badge` defers them.
+        if IRQ_CAPS.try_lock().is_none() {
+            continue;
+        }
+        let badge = DISPATCHER_SPILLED.swap(0, Ordering::AcqRel);
+        handle_badge(0, 0, badge);
+    }
+}
+
+/// Relays the badges of a notification bank after the first one, see
+/// [`IrqCap::new_bank`].
+struct BankRelay {
+    thread: LocalThread,
+    notify: Notification,
+    /// Notification minted from the first bank with the summary bit.
+    summary: Notification,
+    /// Badge bits received and not handled yet.
+    pending: AtomicU64,
+}
+
+/// Entry of a bank relay thread.
+///
+/// It only touches atomics and signals, so it never waits for a lock held by
+/// the thread it interrupts.
+extern "C" fn relay_entry(relay: usize) -> ! {
+    let relay = unsafe { &*(relay as *const BankRelay) };
+    relay.thread.install_ipc_buffer();
+    loop {
+        let badge = relay.notify.wait();
+        relay.pending.fetch_or(badge as _, Ordering::AcqRel);
+        relay.summary.signal();
+    }
+}
+
+/// Raises a registered seL4 IRQ as if the kernel delivered it, by signalling
+/// its notification. It returns `false` if the IRQ is not registered.
+pub(crate) fn raise_irq(irq: usize) -> bool {
+    let notify = irq_caps().notifications.get(&irq).copied();
+    notify.inspect(|notify| notify.signal()).is_some()
+}
+
+/// Registers a platform-internal IRQ handler and binds it to a seL4 IRQ.
+///
+/// It returns `Ok(false)` if a handler is already registered for the IRQ. If
+/// binding the seL4 IRQ fails, the handler is unregistered again.
+pub(crate) fn register(irq: usize, handler: IrqHandler) -> InitResult<bool> {
+    if !IRQ_HANDLER_TABLE.register_handler(irq as _, handler) {
+        return Ok(false);
+    }
+    if let Err(err) = irq_caps().register_sel4_irq(irq) {
+        IRQ_HANDLER_TABLE.unregister_handler(irq as _);
+        return Err(err);
+    }
+    Ok(true)
+}
+
+/// Trigger mode of an IRQ.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IrqTrigger {
+    #[default]
+    Level,
+    Edge,
+}
+
+/// How an IRQ is set up in the interrupt controller.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct IrqConfig {
+    pub trigger: IrqTrigger,
+    /// Core the IRQ is routed to, or [`None`] to leave it to the kernel.
+    pub core: Option<usize>,
+}
+
+impl IrqConfig {
+    /// Returns the configuration of `irq` in the `irq-config` table, or the
+    /// default one if it is not listed.
+    pub fn from_config(irq: usize) -> Self {
+        IRQ_CONFIG
+            .iter()
+            .find(|&&(idx, _, _)| idx == irq)
+            .map_or_else(Self::default, |&(_, edge_triggered, core)| Self {
+                trigger: if edge_triggered != 0 {
+                    IrqTrigger::Edge
+                } else {
+                    IrqTrigger::Level
+                },
+                core: Some(core),
+            })
+    }
+}
+
+/// Registers an IRQ handler and binds it to a seL4 IRQ set up with `config`,
+/// which takes precedence over the `irq-config` table.
+///
+/// It returns `false` if a handler is already registered for the IRQ, if the
+/// seL4 IRQ is already bound with a different configuration, or if the
+/// registration failed, e.g. because the kernel cannot route the IRQ to the
+/// requested core. The previous configuration is kept in that case.
+pub fn register_with_config(irq: usize, handler: IrqHandler, config: IrqConfig) -> bool {
+    let prev = {
+        let mut caps = irq_caps();
+        if caps.irq_handlers.contains_key(&irq) && caps.irq_config(irq) != config {
+            return false;
+        }
+        caps.irq_configs.insert(irq, config)
+    };
+    if IrqIfImpl::register(irq, handler) {
+        return true;
+    }
+    let mut caps = irq_caps();
+    match prev {
+        Some(prev) => caps.irq_configs.insert(irq, prev),
+        None => caps.irq_configs.remove(&irq),
+    };
+    false
+}
+
+/// Binds a notification for IPIs to the thread of a secondary CPU.
+#[cfg(feature = "smp")]
+pub(crate) fn init_secondary(cpu_id: usize, tcb: sel4::cap::Tcb) -> InitResult {
+    let notify = alloc_notification()?;
+    tcb.tcb_bind_notification(notify).map_err(PlatformInitError::IrqBindFailed)?;
+    irq_caps().cpu_notifications.insert(cpu_id, notify);
+    Ok(())
+}
+
+/// Blocks on the notification bound to the current CPU and handles the
+/// delivered IRQ or IPI.
+///
+/// With the dispatcher thread serving the primary CPU, it yields instead.
+/// It fails if no notification is bound to the current CPU.
+pub(crate) fn wait_and_handle_irq() -> sel4::Result<()> {
+    let cpu_id = axplat::percpu::this_cpu_id();
+    if cpu_id == 0 && dispatch_mode() == DispatchMode::Dispatcher {
+        sel4::r#yield();
+        return Ok(());
+    }
+    let notify = cpu_notification(cpu_id)?;
+    let badge = notify.wait();
+    handle_badge(cpu_id, 0, badge as _);
+    Ok(())
+}
+
+/// Waits for IRQs on the current CPU when it is idle.
+///
+/// In yield mode, the primary CPU handles what is pending on its notification,
+/// e.g. IPIs targeted at it, and yields if there is nothing.
+pub(crate) fn wait_for_irqs() {
+    let cpu_id = axplat::percpu::this_cpu_id();
+    let res = if cpu_id == 0 && dispatch_mode() == DispatchMode::Yield {
+        cpu_notification(cpu_id).map(|notify| {
+            let (_, badge) = notify.poll();
+            handle_badge(cpu_id, 0, badge as _);
+            if badge == 0 {
+                sel4::r#yield();
+            }
+        })
+    } else {
+        wait_and_handle_irq()
+    };
+    if let Err(err) = res {
+        log::error!("Failed to wait for IRQs on CPU {}: {:?}", cpu_id, err);
+        sel4::r#yield();
+    }
+}
+
+/// Returns the notification bound to the thread of CPU `cpu_id`.
+fn cpu_notification(cpu_id: usize) -> sel4::Result<Notification> {
+    irq_caps()
+        .cpu_notifications
+        .get(&cpu_id)
+        .copied()
+        .ok_or(sel4::Error::FailedLookup)
+}
+
+/// Handles the IRQs relayed from the notification banks after the first one.
+fn poll_irq_banks(cpu_id: usize) {
+    let num_banks = irq_caps().banks.len();
+    for bank in 1..num_banks {
+        let relay = irq_caps().banks[bank].relay;
+        let badge = relay.map_or(0, |relay| relay.pending.swap(0, Ordering::AcqRel));
+        if badge != 0 {
+            handle_badge(cpu_id, bank, badge as _);
+        }
+    }
+}
+
+/// Prints the registered seL4 IRQs and their capabilities.
+pub(crate) fn dump() {
+    // read before `irq_caps` disables them
+    let enabled = irqs_enabled();
+    let caps = irq_caps();
+    axplat::console_println!(
+        "sysrq: IRQs {}, global notification {:#x}:",
+        if enabled {
+            "enabled"
+        } else {
+            "disabled"
+        },
+        caps.global_notify.bits()
+    );
+    for (irq, handler) in caps.irq_handlers.iter() {
+        axplat::console_println!(
+            "  irq {}: handler {:#x}, notification {:#x}, bank {} bit {}{}",
+            irq,
+            handler.bits(),
+            caps.notifications.get(irq).map_or(0, |n| n.bits()),
+            caps.irq_bits.get(irq).map_or(0, |b| b.0),
+            caps.irq_bits.get(irq).map_or(0, |b| b.1),
+            if caps.masked.contains_key(irq) {
+                ", masked"
+            } else {
+                ""
+            }
+        );
+    }
 }
 
+/// Handles the IRQs signalled in the badge of the first notification bank,
+/// and those relayed from the other banks if its summary bit is set.
 pub fn handle_irq(badge: usize) {
-    handle_trap!(IRQ, badge as _);
-    IRQ_CAPS.lock().ack_irq(badge as _);
+    handle_badge(axplat::percpu::this_cpu_id(), 0, badge);
 }
 
+/// Dispatches and acknowledges every IRQ whose bit is set in `badge`, or
+/// defers it if IRQs are disabled on the CPU.
+fn handle_badge(cpu_id: usize, bank: usize, badge: usize) {
+    let wakeup = current_ticks();
+    let summary = bank == 0 && badge & (1 << SUMMARY_BIT) != 0;
+    let mut bits = if summary {
+        badge & !(1 << SUMMARY_BIT)
+    } else {
+        badge
+    };
+    while bits != 0 {
+        let bit = bits.trailing_zeros() as usize;
+        bits &= bits - 1;
+        let mut caps = irq_caps();
+        let Some(irq) = caps.irq_of(bank, bit) else {
+            caps.spurious += 1;
+            log::warn!("Spurious IRQ badge bit {} in bank {}", bit, bank);
+            continue;
+        };
+        caps.stats.entry(irq).or_default().delivered += 1;
+        if caps.defer_masked(irq) {
+            continue;
+        }
+        drop(caps);
+        let state = &CPU_IRQ_STATES[cpu_id];
+        if state.enabled() {
+            dispatch_irq(cpu_id, irq, Some(wakeup));
+        } else {
+            state.set_pending(irq);
+        }
+    }
+    if summary {
+        poll_irq_banks(cpu_id);
+    }
+}
+
+/// Runs the handler of an IRQ on behalf of CPU `cpu_id` and acknowledges it.
+///
+/// IRQs are disabled on the CPU while the handler runs, so that no deferred
+/// IRQ is replayed from inside it. The latency from `wakeup` to the
+/// acknowledgement is recorded, IRQs replayed after being deferred have none.
+fn dispatch_irq(cpu_id: usize, irq: usize, wakeup: Option<u64>) {
+    let state = &CPU_IRQ_STATES[cpu_id];
+    let was_disabled = state.disabled.swap(true, Ordering::AcqRel);
+    // the timer IRQ is shared with the software timers, its handler only runs
+    // when the one-shot timer expired
+    if irq != TIMER_IRQ || crate::time::handle_timer_expiry() {
+        handle_trap!(IRQ, irq);
+    }
+    let mut caps = irq_caps();
+    caps.ack_irq(irq);
+    if let Some(wakeup) = wakeup {
+        let latency = current_ticks().saturating_sub(wakeup);
+        caps.stats.entry(irq).or_default().record_latency(latency);
+    }
+    drop(caps);
+    state.disabled.store(was_disabled, Ordering::Release);
+}
+
+/// Handles the IRQs deferred on the current CPU while it had IRQs disabled.
+fn replay_pending_irqs() {
+    let cpu_id = axplat::percpu::this_cpu_id();
+    let state = &CPU_IRQ_STATES[cpu_id];
+    while state.enabled() {
+        let Some(irq) = state.take_pending() else {
+            break;
+        };
+        if !irq_caps().defer_masked(irq) {
+            dispatch_irq(cpu_id, irq, None);
+        }
+    }
+}
+
+/// Number of buckets of the latency histogram of [`IrqStats`].
+pub const LATENCY_BUCKETS: usize = 32;
+
+/// Counters of an IRQ.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct IrqStats {
+    /// Times the IRQ was delivered by the kernel.
+    pub delivered: u64,
+    /// Times a registered handler ran.
+    pub handled: u64,
+    /// Times no handler was registered when it was handled.
+    pub unhandled: u64,
+    /// Latency from the notification wakeup to the acknowledgement in ticks,
+    /// bucket `i` counts latencies in `[2^i, 2^(i+1))`, the last one also
+    /// counts all longer ones.
+    pub latency_hist: [u64; LATENCY_BUCKETS],
+    /// Longest latency from the notification wakeup to the acknowledgement in ticks.
+    pub max_latency: u64,
+}
+
+impl IrqStats {
+    fn record_latency(&mut self, ticks: u64) {
+        let bucket = (ticks.max(1).ilog2() as usize).min(LATENCY_BUCKETS - 1);
+        self.latency_hist[bucket] += 1;
+        self.max_latency = self.max_latency.max(ticks);
+    }
+}
+
+/// Returns the counters of every IRQ that has been delivered or handled.
+pub fn stats() -> Vec<(usize, IrqStats)> {
+    irq_caps()
+        .stats
+        .iter()
+        .map(|(&irq, &stats)| (irq, stats))
+        .collect()
+}
+
+/// Returns the number of badge bits delivered without an IRQ assigned.
+pub fn spurious_irqs() -> u64 {
+    irq_caps().spurious
+}
+
+/// Prints the IRQ counters and latency histograms.
+pub(crate) fn dump_stats() {
+    axplat::console_println!("sysrq: IRQ statistics, {} spurious:", spurious_irqs());
+    for (irq, stats) in stats() {
+        axplat::console_println!(
+            "  irq {}: delivered {}, handled {}, unhandled {}, max latency {} ns",
+            irq,
+            stats.delivered,
+            stats.handled,
+            stats.unhandled,
+            ticks_to_nanos(stats.max_latency)
+        );
+        for (bucket, &count) in stats.latency_hist.iter().enumerate() {
+            if count != 0 {
+                axplat::console_println!("    >= {} ns: {}", ticks_to_nanos(1 << bucket), count);
+            }
+        }
+    }
+}
+
+/// Interrupt state of a CPU.
+struct CpuIrqState {
+    /// Whether IRQs are disabled, see [`disable_irqs`].
+    disabled: AtomicBool,
+    /// IRQs delivered while IRQs were disabled.
+    pending: [AtomicU64; PENDING_WORDS],
+}
+
+impl CpuIrqState {
+    const fn new() -> Self {
+        Self {
+            disabled: AtomicBool::new(true),
+            pending: [const { AtomicU64::new(0) }; PENDING_WORDS],
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        !self.disabled.load(Ordering::Acquire)
+    }
+
+    fn set_pending(&self, irq: usize) {
+        self.pending[irq / 64].fetch_or(1 << (irq % 64), Ordering::AcqRel);
+    }
+
+    /// Takes the lowest pending IRQ.
+    fn take_pending(&self) -> Option<usize> {
+        for (idx, word) in self.pending.iter().enumerate() {
+            let bits = word.load(Ordering::Acquire);
+            if bits != 0 {
+                let bit = bits.trailing_zeros() as usize;
+                word.fetch_and(!(1 << bit), Ordering::AcqRel);
+                return Some(idx * 64 + bit);
+            }
+        }
+        None
+    }
+}
+
+fn this_cpu_state() -> &'static CpuIrqState {
+    &CPU_IRQ_STATES[axplat::percpu::this_cpu_id()]
+}
+
+/// Returns whether IRQs are enabled on the current CPU.
 #[inline(always)]
 pub fn irqs_enabled() -> bool {
-    IRQ_CAPS.lock().irqs_enabled()
+    this_cpu_state().enabled()
 }
 
-#[inline(always)]
+/// Enables IRQs on the current CPU and handles the IRQs deferred while they
+/// were disabled.
 pub fn enable_irqs() {
-    IRQ_CAPS.lock().enable_irqs();
+    this_cpu_state().disabled.store(false, Ordering::Release);
+    replay_pending_irqs();
 }
 
+/// Disables IRQs on the current CPU. Calls do not nest, use
+/// [`local_irq_save`] for sections that may be entered with IRQs disabled.
 #[inline(always)]
 pub fn disable_irqs() {
-    IRQ_CAPS.lock().disable_irqs();
+    this_cpu_state().disabled.store(true, Ordering::Release);
+}
+
+/// Disables IRQs on the current CPU and returns the previous state for
+/// [`local_irq_restore`].
+pub fn local_irq_save() -> usize {
+    this_cpu_state().disabled.swap(true, Ordering::AcqRel) as usize
+}
+
+/// Restores the IRQ state saved by [`local_irq_save`].
+pub fn local_irq_restore(flags: usize) {
+    if flags == 0 {
+        enable_irqs();
+    } else {
+        disable_irqs();
+    }
+}
+
+/// A notification whose badge bits are assigned to IRQs.
+struct IrqBank {
+    notify: Notification,
+    /// IRQ assigned to each badge bit.
+    irqs: [Option<usize>; IRQS_PER_BANK],
+    /// Relay of the bank, [`None`] for the first one.
+    relay: Option<&'static BankRelay>,
+}
+
+impl IrqBank {
+    fn new(notify: Notification, relay: Option<&'static BankRelay>) -> Self {
+        Self {
+            notify,
+            irqs: [None; IRQS_PER_BANK],
+            relay,
+        }
+    }
 }
 
 /// Represents the IRQ capabilities and handlers for the seL4 platform.
 /// It manages the global notification for IRQs, the IRQ handlers, and the task that handles IRQs
 struct IrqCap {
-    enable: bool,
     global_notify: Notification,
+    /// Notification banks, the first one is `global_notify`.
+    banks: Vec<IrqBank>,
+    /// Bank index and badge bit of each IRQ.
+    irq_bits: BTreeMap<usize, (usize, usize)>,
     irq_handlers: BTreeMap<usize, Sel4IrqHandler>,
     notifications: BTreeMap<usize, Notification>,
+    /// IRQs disabled with `set_enable`, and whether they fired while disabled.
+    masked: BTreeMap<usize, bool>,
+    /// Notification bound to each CPU thread, the primary CPU uses `global_notify`.
+    cpu_notifications: BTreeMap<usize, Notification>,
+    /// Notifications minted for IPIs, keyed by target CPU and IRQ number.
+    ipi_notifications: BTreeMap<(usize, usize), Notification>,
+    /// Configurations passed to [`register_with_config`].
+    irq_configs: BTreeMap<usize, IrqConfig>,
+    stats: BTreeMap<usize, IrqStats>,
+    /// Badge bits delivered without an IRQ assigned.
+    spurious: u64,
 }
 
 impl IrqCap {
@@ -79,61 +722,298 @@ impl IrqCap {
         let irq_handlers = BTreeMap::new();
         let notifications = BTreeMap::new();
         Self {
-            enable: false,
             global_notify,
+            banks: Vec::new(),
+            irq_bits: BTreeMap::new(),
             irq_handlers,
             notifications,
+            masked: BTreeMap::new(),
+            cpu_notifications: BTreeMap::new(),
+            ipi_notifications: BTreeMap::new(),
+            irq_configs: BTreeMap::new(),
+            stats: BTreeMap::new(),
+            spurious: 0,
         }
     }
 
-    /// Initializes the IRQ capabilities and task.
-    pub(crate) fn init(&mut self) -> sel4::Result<()> {
+    /// Initializes the IRQ capabilities and task, returns the global notification.
+    ///
+    /// It is bound to the main thread unless a dispatcher thread serves it.
+    pub(crate) fn init(&mut self) -> InitResult<Notification> {
         // create a global notification for IRQs
-        self.global_notify = OBJ_ALLOCATOR.alloc_notification();
-        self.enable = true;
+        self.global_notify = alloc_notification()?;
+        self.banks.push(IrqBank::new(self.global_notify, None));
 
-        sel4::init_thread::slot::TCB
-            .cap()
-            .tcb_bind_notification(self.global_notify)?;
+        if dispatch_mode() != DispatchMode::Dispatcher {
+            sel4::init_thread::slot::TCB
+                .cap()
+                .tcb_bind_notification(self.global_notify)
+                .map_err(PlatformInitError::IrqBindFailed)?;
+        }
+        self.cpu_notifications.insert(0, self.global_notify);
 
-        Ok(())
+        Ok(self.global_notify)
     }
 
-    pub(crate) fn enable_irqs(&mut self) {
-        self.enable = true;
+    /// Returns the IRQ assigned to `bit` of the given bank.
+    fn irq_of(&self, bank: usize, bit: usize) -> Option<usize> {
+        self.banks.get(bank)?.irqs[bit]
     }
 
-    pub(crate) fn disable_irqs(&mut self) {
-        self.enable = false;
+    /// Returns the bank and badge bit of an IRQ, assigning a free one if it has
+    /// none yet. IPIs are always placed in the first bank.
+    fn irq_bit(&mut self, irq: usize) -> InitResult<(usize, usize)> {
+        if let Some(&pos) = self.irq_bits.get(&irq) {
+            return Ok(pos);
+        }
+        let banks = if irq < SGI_COUNT { 1 } else { usize::MAX };
+        let free = self
+            .banks
+            .iter()
+            .take(banks)
+            .enumerate()
+            .find_map(|(bank, b)| {
+                let bit = b
+                    .irqs
+                    .iter()
+                    .enumerate()
+                    .position(|(bit, assigned)| {
+                        assigned.is_none() && (bank, bit) != (0, SUMMARY_BIT)
+                    })?;
+                Some((bank, bit))
+            });
+        let (bank, bit) = match free {
+            Some(pos) => pos,
+            None if self.banks.is_empty() || irq < SGI_COUNT => {
+                return Err(PlatformInitError::IrqBindFailed(
+                    sel4::Error::NotEnoughMemory,
+                ));
+            }
+            None => {
+                let bank = self.new_bank()?;
+                self.banks.push(bank);
+                (self.banks.len() - 1, 0)
+            }
+        };
+        self.banks[bank].irqs[bit] = Some(irq);
+        self.irq_bits.insert(irq, (bank, bit));
+        Ok((bank, bit))
     }
 
-    pub(crate) fn irqs_enabled(&self) -> bool {
-        self.enable
+    /// Creates a notification bank after the first one, with a thread relaying
+    /// its badges to the summary bit of the first bank.
+    fn new_bank(&self) -> InitResult<IrqBank> {
+        let notify = alloc_notification()?;
+        let slot = alloc_slot()?;
+        if let Err(err) = LeafSlot::from_cap(self.global_notify).mint_to(
+            slot,
+            sel4::CapRights::all(),
+            1 << SUMMARY_BIT,
+        ) {
+            recycle_slot(slot);
+            return Err(PlatformInitError::IrqBindFailed(err));
+        }
+        let thread = LocalThread::new(DISPATCHER_PRIORITY)?;
+        let relay: &'static BankRelay = Box::leak(Box::new(BankRelay {
+            thread,
+            notify,
+            summary: slot.cap(),
+            pending: AtomicU64::new(0),
+        }));
+        alloc_stack(RELAY_STACK_SIZE)
+            .and_then(|stack_top| {
+                thread.start(relay_entry, stack_top, relay as *const BankRelay as usize)
+            })
+            .map_err(PlatformInitError::IrqBindFailed)?;
+        Ok(IrqBank::new(notify, Some(relay)))
+    }
+
+    /// Releases the badge bit of an IRQ.
+    fn free_irq_bit(&mut self, irq: usize) {
+        if let Some((bank, bit)) = self.irq_bits.remove(&irq) {
+            self.banks[bank].irqs[bit] = None;
+        }
+    }
+
+    /// Enables or disables a single IRQ.
+    ///
+    /// A disabled IRQ stays registered, but it is not acknowledged when it
+    /// fires so the kernel stops delivering it. Enabling an IRQ registers it if
+    /// needed, and acknowledges it if it fired while disabled.
+    pub fn set_irq_enabled(&mut self, idx: usize, enabled: bool) -> InitResult {
+        if !enabled {
+            if self.irq_bits.contains_key(&idx) {
+                self.masked.entry(idx).or_insert(false);
+            }
+            return Ok(());
+        }
+        if self.masked.remove(&idx) == Some(true) {
+            self.ack_irq(idx);
+        }
+        self.register_sel4_irq(idx)
+    }
+
+    /// Records that a disabled IRQ fired, returns `false` if it is enabled.
+    fn defer_masked(&mut self, idx: usize) -> bool {
+        match self.masked.get_mut(&idx) {
+            Some(fired) => {
+                *fired = true;
+                true
+            }
+            None => false,
+        }
     }
 
     /// Registers a seL4 IRQ and sets up the necessary capabilities and notifications.
-    pub fn register_sel4_irq(&mut self, idx: usize) -> sel4::Result<()> {
+    ///
+    /// It does nothing if the IRQ is already registered. On failure, what was
+    /// set up so far is released again.
+    pub fn register_sel4_irq(&mut self, idx: usize) -> InitResult {
+        if self.irq_handlers.contains_key(&idx) {
+            return Ok(());
+        }
+        let (bank, bit) = self.irq_bit(idx)?;
+        if idx < SGI_COUNT {
+            // IPIs are signalled by `send_ipi`, not by the kernel
+            return Ok(());
+        }
+        let res = self.bind_sel4_irq(idx, bank, bit);
+        if res.is_err() {
+            let _ = self.remove_sel4_irq(idx);
+        }
+        res
+    }
+
+    /// Creates the notification and IRQ handler of a seL4 IRQ signalling
+    /// `bit` of `bank`.
+    fn bind_sel4_irq(&mut self, idx: usize, bank: usize, bit: usize) -> InitResult {
         // create a notification for the IRQ
-        let slot = alloc_slot();
-        LeafSlot::from_cap(self.global_notify).mint_to(slot, sel4::CapRights::all(), idx as _)?;
+        let slot = alloc_slot()?;
+        if let Err(err) = LeafSlot::from_cap(self.banks[bank].notify).mint_to(
+            slot,
+            sel4::CapRights::all(),
+            1 << bit,
+        ) {
+            recycle_slot(slot);
+            return Err(PlatformInitError::IrqBindFailed(err));
+        }
         let notify = slot.cap();
         self.notifications.insert(idx, notify);
 
-        // create an IRQ handler
-        let irq_handler = alloc_slot().cap();
-        register_irq(idx as _, irq_handler.into());
+        // create an IRQ handler, from our own IRQ control cap if the root task handed one over
+        let slot = alloc_slot()?;
+        if let Err(err) = self.get_irq_handler(idx, slot) {
+            recycle_slot(slot);
+            return Err(PlatformInitError::IrqBindFailed(err));
+        }
+        let irq_handler: Sel4IrqHandler = slot.cap();
+        self.irq_handlers.insert(idx, irq_handler);
 
         // set up the IRQ handler
-        irq_handler.irq_handler_set_notification(notify)?;
-        irq_handler.irq_handler_ack()?;
-        self.irq_handlers.insert(idx, irq_handler);
+        irq_handler
+            .irq_handler_set_notification(notify)
+            .and_then(|_| irq_handler.irq_handler_ack())
+            .map_err(PlatformInitError::IrqBindFailed)
+    }
+
+    /// Returns the configuration `idx` is set up with.
+    fn irq_config(&self, idx: usize) -> IrqConfig {
+        self.irq_configs
+            .get(&idx)
+            .copied()
+            .unwrap_or_else(|| IrqConfig::from_config(idx))
+    }
 
+    /// Obtains the IRQ handler of `idx` into the empty `slot`.
+    fn get_irq_handler(&self, idx: usize, slot: LeafSlot) -> sel4::Result<()> {
+        let config = self.irq_config(idx);
+        let edge_triggered = config.trigger == IrqTrigger::Edge;
+        match boot_info().irq_control {
+            0 if config == IrqConfig::default() => {
+                register_irq(idx as _, slot);
+            }
+            0 => {
+                let core = config.core.unwrap_or(crate::ipc::IRQ_CORE_ANY);
+                crate::ipc::register_irq(idx, edge_triggered, core, slot)?;
+            }
+            irq_control => {
+                let irq_control = IrqControl::from_bits(irq_control);
+                let dst = slot.abs_cptr();
+                match config.core {
+                    _ if config == IrqConfig::default() => {
+                        irq_control.irq_control_get(idx as _, &dst)?;
+                    }
+                    Some(core) => {
+                        irq_control_get_trigger_core(irq_control, idx, edge_triggered, core, &dst)?;
+                    }
+                    None => {
+                        irq_control.irq_control_get_trigger(idx as _, edge_triggered, &dst)?;
+                    }
+                }
+            }
+        }
         Ok(())
     }
 
-    pub fn remove_sel4_irq(&mut self, idx: usize) {
-        self.notifications.remove(&idx);
-        self.irq_handlers.remove(&idx);
+    /// Sends an IPI to the given CPU by signalling its notification with the
+    /// badge bit of the IRQ.
+    pub fn send_ipi(&mut self, irq: usize, cpu_id: usize) -> InitResult {
+        let notify = match self.ipi_notifications.get(&(cpu_id, irq)) {
+            Some(notify) => *notify,
+            None => {
+                let invalid = PlatformInitError::IrqBindFailed(sel4::Error::InvalidArgument);
+                let cpu_notify = *self.cpu_notifications.get(&cpu_id).ok_or(invalid)?;
+                // the badge is decoded against the first bank on every CPU,
+                // where only SGIs are placed
+                if irq >= SGI_COUNT {
+                    return Err(invalid);
+                }
+                let (_, bit) = self.irq_bit(irq)?;
+                let slot = alloc_slot()?;
+                if let Err(err) =
+                    LeafSlot::from_cap(cpu_notify).mint_to(slot, sel4::CapRights::all(), 1 << bit)
+                {
+                    recycle_slot(slot);
+                    return Err(PlatformInitError::IrqBindFailed(err));
+                }
+                let notify = slot.cap();
+                self.ipi_notifications.insert((cpu_id, irq), notify);
+                notify
+            }
+        };
+        notify.signal();
+        Ok(())
+    }
+
+    /// Unregisters a seL4 IRQ, deletes its capabilities and recycles their
+    /// slots, so that the IRQ can be registered again.
+    ///
+    /// Everything is released even if a step fails, the first error is
+    /// returned at the end.
+    pub fn remove_sel4_irq(&mut self, idx: usize) -> sel4::Result<()> {
+        let mut res = Ok(());
+        if let Some(irq_handler) = self.irq_handlers.remove(&idx) {
+            // stop the kernel from delivering the IRQ first
+            res = res.and(irq_handler.irq_handler_clear());
+            res = res.and(delete_slot(LeafSlot::from_cap(irq_handler)));
+        }
+        if let Some(notify) = self.notifications.remove(&idx) {
+            res = res.and(delete_slot(LeafSlot::from_cap(notify)));
+        }
+        let ipi_keys: Vec<_> = self
+            .ipi_notifications
+            .keys()
+            .filter(|&&(_, irq)| irq == idx)
+            .copied()
+            .collect();
+        for key in ipi_keys {
+            if let Some(notify) = self.ipi_notifications.remove(&key) {
+                res = res.and(delete_slot(LeafSlot::from_cap(notify)));
+            }
+        }
+        self.masked.remove(&idx);
+        self.free_irq_bit(idx);
+        res
     }
 
     pub fn ack_irq(&self, idx: usize) {
@@ -143,6 +1023,41 @@ impl IrqCap {
     }
 }
 
+/// Obtains the IRQ handler of `irq` routed to `core` into `dst`.
+#[sel4::sel4_cfg(not(MAX_NUM_NODES = "1"))]
+fn irq_control_get_trigger_core(
+    irq_control: IrqControl,
+    irq: usize,
+    edge_triggered: bool,
+    core: usize,
+    dst: &sel4::AbsoluteCPtr,
+) -> sel4::Result<()> {
+    irq_control.irq_control_get_trigger_core(irq as _, edge_triggered, core as _, dst)
+}
+
+/// Obtains the IRQ handler of `irq` into `dst`, a single-node kernel routes
+/// every IRQ to core 0.
+#[sel4::sel4_cfg(MAX_NUM_NODES = "1")]
+fn irq_control_get_trigger_core(
+    irq_control: IrqControl,
+    irq: usize,
+    edge_triggered: bool,
+    core: usize,
+    dst: &sel4::AbsoluteCPtr,
+) -> sel4::Result<()> {
+    if core != 0 {
+        return Err(sel4::Error::InvalidArgument);
+    }
+    irq_control.irq_control_get_trigger(irq as _, edge_triggered, dst)
+}
+
+/// Deletes the capability in a slot allocated by this module and recycles the slot.
+fn delete_slot(slot: LeafSlot) -> sel4::Result<()> {
+    slot.abs_cptr().delete()?;
+    recycle_slot(slot);
+    Ok(())
+}
+
 struct IrqIfImpl;
 
 /// Implementation of the Arceos IRQ interface for the seL4 platform.
@@ -151,13 +1066,8 @@ struct IrqIfImpl;
 impl IrqIf for IrqIfImpl {
     /// Enables or disables the given IRQ.
     fn set_enable(irq: usize, enabled: bool) {
-        if enabled {
-            IRQ_CAPS.lock().register_sel4_irq(irq).unwrap();
-        } else {
-            log::warn!(
-                "Disabling IRQ on seL4 platform {} is not supported now!",
-                irq
-            );
+        if let Err(err) = irq_caps().set_irq_enabled(irq, enabled) {
+            log::warn!("Failed to set IRQ {} enabled to {}: {}", irq, enabled, err);
         }
     }
 
@@ -166,12 +1076,10 @@ impl IrqIf for IrqIfImpl {
     /// It also enables the IRQ if the registration succeeds. It returns `false`
     /// if the registration failed.
     fn register(irq: usize, handler: IrqHandler) -> bool {
-        if IRQ_HANDLER_TABLE.register_handler(irq as _, handler) {
-            IRQ_CAPS.lock().register_sel4_irq(irq).unwrap();
-            return true;
-        }
-
-        false
+        register(irq, handler).unwrap_or_else(|err| {
+            log::warn!("Failed to register IRQ {}: {}", irq, err);
+            false
+        })
     }
 
     /// Unregisters the IRQ handler for the given IRQ.
@@ -179,7 +1087,9 @@ impl IrqIf for IrqIfImpl {
     /// It also disables the IRQ if the unregistration succeeds. It returns the
     /// existing handler if it is registered, `None` otherwise.
     fn unregister(irq: usize) -> Option<IrqHandler> {
-        IRQ_CAPS.lock().remove_sel4_irq(irq);
+        if let Err(err) = irq_caps().remove_sel4_irq(irq) {
+            log::warn!("Failed to remove seL4 IRQ {}: {:?}", irq, err);
+        }
         IRQ_HANDLER_TABLE.unregister_handler(irq as _)
     }
 
@@ -189,11 +1099,38 @@ impl IrqIf for IrqIfImpl {
     /// IRQ handler table and calls the corresponding handler. If necessary, it
     /// also acknowledges the interrupt controller after handling.
     fn handle(irq: usize) {
-        if !IRQ_HANDLER_TABLE.handle(irq as _) {
+        let handled = IRQ_HANDLER_TABLE.handle(irq as _);
+        let mut caps = irq_caps();
+        let stats = caps.stats.entry(irq).or_default();
+        if handled {
+            stats.handled += 1;
+        } else {
+            stats.unhandled += 1;
             log::warn!("Unhandled IRQ {}", irq);
         }
     }
 
     /// Sends an inter-processor interrupt (IPI) to the specified target CPU or all CPUs.
-    fn send_ipi(irq_num: usize, target: IpiTarget) {}
+    ///
+    /// The target CPU thread receives it on its bound notification and
+    /// dispatches it as `irq_num`.
+    fn send_ipi(irq_num: usize, target: IpiTarget) {
+        let mut caps = irq_caps();
+        let mut send = |cpu_id: usize| {
+            if let Err(err) = caps.send_ipi(irq_num, cpu_id) {
+                log::warn!(
+                    "Failed to send IPI {} to CPU {}: {}",
+                    irq_num,
+                    cpu_id,
+                    err
+                );
+            }
+        };
+        match target {
+            IpiTarget::Current { cpu_id } | IpiTarget::Other { cpu_id } => send(cpu_id),
+            IpiTarget::AllExceptCurrent { cpu_id, cpu_num } => {
+                (0..cpu_num).filter(|&id| id != cpu_id).for_each(send);
+            }
+        }
+    }
 }
@@ -6,13 +6,17 @@ extern crate axplat;
 extern crate alloc;
 extern crate uart_thread;
 
-mod console;
-mod init;
+pub mod console;
+pub mod dmesg;
+pub mod init;
 #[cfg(feature = "irq")]
 pub mod irq;
 mod mem;
 mod power;
-mod time;
+#[cfg(feature = "smp")]
+mod smp;
+mod sysrq;
+pub mod time;
 
 pub mod utils;
 pub use utils::task;
@@ -21,6 +25,8 @@ pub mod ipc;
 pub use ipc::*;
 
 pub mod asm;
+pub mod bootinfo;
+pub mod error;
 
 pub mod config {
     //! Platform configuration module.
@@ -37,7 +43,9 @@ pub mod config {
     );
 }
 
+/// Entry point, `arg` is the address of the [`bootinfo::BootInfo`] placed by
+/// the root task, or 0.
 #[unsafe(no_mangle)]
-unsafe extern "C" fn _start() -> ! {
-    axplat::call_main(0, 0);
+unsafe extern "C" fn _start(arg: usize) -> ! {
+    axplat::call_main(0, arg);
 }
@@ -4,8 +4,10 @@ use axplat::mem::{MemIf, PhysAddr, RawRange, VirtAddr};
 use common::root::translate_addr;
 use common::ObjectAllocator;
 
+use crate::bootinfo::boot_info;
+use crate::error::{InitResult, PlatformInitError};
 use crate::config::devices::MMIO_RANGES;
-use crate::utils::obj::alloc_pt;
+use crate::utils::obj::{retype, try_alloc_pt};
 use alloc::collections::BTreeMap;
 use alloc::vec::Vec;
 use kspin::SpinNoIrq;
@@ -18,6 +20,9 @@ const MEM_SIZE: usize = crate::config::plat::VIRT_MEMORY_SIZE;
 const VIRT_FRAME_ADDR: usize = crate::config::plat::VIRT_FRAME_BASE;
 const VIRT_FRAME_SIZE: usize = crate::config::plat::VIRT_FRAME_SIZE;
 
+const MMIO_VADDR_BASE: usize = crate::config::plat::MMIO_VADDR_BASE;
+const MMIO_VADDR_SIZE: usize = crate::config::plat::MMIO_VADDR_SIZE;
+
 const LARGE_PAGE_SIZE: usize = 0x200000; // 2MB
 const PAGE_SIZE: usize = 0x1000; // 4KB
 
@@ -25,12 +30,12 @@ const PAGE_SIZE: usize = 0x1000; // 4KB
 pub(crate) static MEM_SPACE: LazyInit<MemSpace> = LazyInit::new();
 
 /// Represents a memory space in the seL4 platform.
-/// The mem_allocator only retype to Large Page Cap, make sure the memory is consequently allocated.
+/// The memory untyped is only retyped to Large Page Caps, make sure the memory is consequently allocated.
 pub(crate) struct MemSpace {
     pub(crate) regions: SpinNoIrq<BTreeMap<usize, RawRange>>,
     pub(crate) vspace: cap::VSpace,
-    pub(crate) mem_allocator: ObjectAllocator,
     pub(crate) vp_allocator: SpinNoIrq<VirtFrameAllocator>,
+    pub(crate) mmio: SpinNoIrq<MmioSpace>,
 }
 
 impl MemSpace {
@@ -38,20 +43,35 @@ impl MemSpace {
         MemSpace {
             regions: SpinNoIrq::new(BTreeMap::new()),
             vspace: sel4::init_thread::slot::VSPACE.cap(),
-            mem_allocator: ObjectAllocator::empty(),
             vp_allocator: SpinNoIrq::new(VirtFrameAllocator::new()),
+            mmio: SpinNoIrq::new(MmioSpace::new()),
         }
     }
 
-    /// Receive the untyped cap from root_task, and used for allocating memory.
+    /// Receive the device caps from root_task, and used for mapping devices.
     pub(crate) fn init(&self) {
-        self.mem_allocator.init(sel4::Cap::from_bits(24));
+        let info = boot_info();
         // add pre allocator heap region
-        let paddr = translate_addr(crate::config::plat::INIT_HEAP_BASE);
-        self.regions.lock().insert(
-            crate::config::plat::INIT_HEAP_BASE,
-            (paddr, paddr + crate::config::plat::INIT_HEAP_SIZE),
-        );
+        let heap = info.heap;
+        let paddr = match heap.paddr {
+            0 => translate_addr(heap.vaddr as _),
+            paddr => paddr as _,
+        };
+        self.regions
+            .lock()
+            .insert(heap.vaddr as _, (paddr, paddr + heap.size as usize));
+        // receive the device untyped and frame caps covering the MMIO regions
+        let mut mmio = self.mmio.lock();
+        for untyped in info.device_untyped() {
+            let allocator = ObjectAllocator::empty();
+            allocator.init(sel4::Cap::from_bits(untyped.cap));
+            mmio.untypeds
+                .insert(untyped.paddr as _, (1 << untyped.size_bits, allocator));
+        }
+        for frame in info.device_frames() {
+            mmio.spare
+                .insert(frame.paddr as _, cap::Granule::from_bits(frame.cap));
+        }
     }
 
     /// Adds a memory region to the memory space.
@@ -60,51 +80,53 @@ impl MemSpace {
     }
 
     /// Maps a memory area to the virtual address space.
-    pub(crate) fn map_area(&self, vaddr: usize, size: usize) {
+    pub(crate) fn map_area(&self, vaddr: usize, size: usize) -> InitResult {
         // only support large page map
         assert_eq!(vaddr % LARGE_PAGE_SIZE, 0);
         assert!(size > 0);
 
-        let caps = self.mem_allocator.alloc_large_pages(size / LARGE_PAGE_SIZE);
+        let untyped = cap::Untyped::from_bits(boot_info().mem_untyped);
+        let caps = (0..size / LARGE_PAGE_SIZE)
+            .map(|_| retype::<sel4::cap_type::LargePage>(untyped))
+            .collect::<InitResult<Vec<_>>>()?;
         let mut total_size: usize = 0;
         let paddr = caps[0]
             .frame_get_address()
-            .expect("can't get address of the physical page");
+            .map_err(|err| PlatformInitError::map_failed(vaddr, err))?;
         for (i, cap) in caps.iter().enumerate() {
-            let vaddr_offset = vaddr + i * LARGE_PAGE_SIZE;
-            self.map_large_page(vaddr_offset, cap);
+            self.map_large_page(vaddr + i * LARGE_PAGE_SIZE, cap)?;
             total_size += LARGE_PAGE_SIZE;
         }
 
         self.add_region(vaddr, paddr, total_size);
+        Ok(())
     }
 
-    fn map_page(&self, vaddr: usize, page: &self::cap::Granule, allocator: &ObjectAllocator) {
+    /// Maps a page, the missing page tables are allocated with `alloc_pt`.
+    fn map_page(
+        &self,
+        vaddr: usize,
+        page: &self::cap::Granule,
+        attrs: sel4::VmAttributes,
+        alloc_pt: impl Fn() -> InitResult<cap::PT>,
+    ) -> InitResult {
         assert_eq!(vaddr % PAGE_SIZE, 0);
         for _ in 0..sel4::vspace_levels::NUM_LEVELS {
-            let res = page.frame_map(
-                self.vspace,
-                vaddr as _,
-                sel4::CapRights::all(),
-                sel4::VmAttributes::DEFAULT,
-            );
+            let res = page.frame_map(self.vspace, vaddr as _, sel4::CapRights::all(), attrs);
             match res {
-                Ok(_) => {
-                    return;
-                }
                 Err(sel4::Error::FailedLookup) => {
-                    let pt_cap = allocator.alloc_pt();
+                    let pt_cap = alloc_pt()?;
                     pt_cap
                         .pt_map(self.vspace, vaddr as _, sel4::VmAttributes::DEFAULT)
-                        .unwrap();
+                        .map_err(|err| PlatformInitError::map_failed(vaddr, err))?;
                 }
-                _ => res.unwrap(),
+                res => return res.map_err(|err| PlatformInitError::map_failed(vaddr, err)),
             }
         }
         unreachable!("Failed to map page at vaddr {:#x}", vaddr);
     }
 
-    fn map_large_page(&self, vaddr: usize, page: &sel4::cap::LargePage) {
+    fn map_large_page(&self, vaddr: usize, page: &sel4::cap::LargePage) -> InitResult {
         assert_eq!(vaddr % LARGE_PAGE_SIZE, 0);
         for _ in 0..sel4::vspace_levels::NUM_LEVELS {
             let res = page.frame_map(
@@ -114,16 +136,13 @@ impl MemSpace {
                 sel4::VmAttributes::DEFAULT,
             );
             match res {
-                Ok(_) => {
-                    return;
-                }
                 Err(sel4::Error::FailedLookup) => {
-                    let pt_cap = alloc_pt();
+                    let pt_cap = try_alloc_pt()?;
                     pt_cap
                         .pt_map(self.vspace, vaddr as _, sel4::VmAttributes::DEFAULT)
-                        .unwrap();
+                        .map_err(|err| PlatformInitError::map_failed(vaddr, err))?;
                 }
-                _ => res.unwrap(),
+                res => return res.map_err(|err| PlatformInitError::map_failed(vaddr, err)),
             }
         }
         unreachable!("Failed to map large page at vaddr {:#x}", vaddr);
@@ -149,24 +168,140 @@ impl MemSpace {
         paddr
     }
 
-    fn alloc_ipc_buffer(
+    /// Maps `ipc_cap` as an IPC buffer at a fixed address, returns the address.
+    fn map_ipc_buffer(
         &self,
-        allocator: &ObjectAllocator,
-    ) -> sel4::Result<(usize, sel4::cap::Granule)> {
-        // Allocate an IPC buffer at a fixed address.
-        let ipc_vpn = self
-            .vp_allocator
-            .lock()
-            .alloc()
-            .ok_or(sel4::Error::NotEnoughMemory)?;
-        let ipc_cap = allocator.alloc_page();
-        self.map_page(ipc_vpn * PAGE_SIZE, &ipc_cap, allocator);
-        Ok((ipc_vpn * PAGE_SIZE, ipc_cap))
+        ipc_cap: &sel4::cap::Granule,
+        alloc_pt: impl Fn() -> InitResult<cap::PT>,
+    ) -> InitResult<usize> {
+        let ipc_vpn = self.vp_allocator.lock().alloc().ok_or(PlatformInitError::MapFailed {
+            addr: VIRT_FRAME_ADDR,
+            err: sel4::Error::NotEnoughMemory,
+        })?;
+        let vaddr = ipc_vpn * PAGE_SIZE;
+        if let Err(err) = self.map_page(vaddr, ipc_cap, sel4::VmAttributes::DEFAULT, alloc_pt) {
+            self.dealloc_ipc_buffer(ipc_vpn);
+            return Err(err);
+        }
+        Ok(vaddr)
     }
 
     fn dealloc_ipc_buffer(&self, vpn: usize) {
         self.vp_allocator.lock().dealloc(vpn);
     }
+
+    /// Maps device memory into the MMIO window, returns the virtual address of `paddr`.
+    ///
+    /// The frames are retyped from the device untyped covering the region and
+    /// mapped uncached. Mapping an already mapped region returns the existing
+    /// mapping, a region that is only partly mapped is rejected. On failure,
+    /// the pages mapped so far are unmapped again.
+    fn map_device(&self, paddr: usize, size: usize) -> InitResult<usize> {
+        let map_failed = |err| PlatformInitError::map_failed(paddr, err);
+        let start = paddr & !(PAGE_SIZE - 1);
+        let end = (paddr + size).next_multiple_of(PAGE_SIZE);
+        let mut mmio = self.mmio.lock();
+        if let Some(vaddr) = mmio.mapping(start, end).map_err(map_failed)? {
+            return Ok(vaddr + (paddr - start));
+        }
+        if mmio.next_vaddr + (end - start) > MMIO_VADDR_BASE + MMIO_VADDR_SIZE {
+            return Err(map_failed(sel4::Error::NotEnoughMemory));
+        }
+
+        let vstart = mmio.next_vaddr;
+        let mut frames = Vec::new();
+        for (i, page) in (start..end).step_by(PAGE_SIZE).enumerate() {
+            let res = mmio.device_frame(page).map_err(map_failed).and_then(|frame| {
+                frames.push((page, frame));
+                let vaddr = vstart + i * PAGE_SIZE;
+                self.map_page(vaddr, &frame, sel4::VmAttributes::NONE, try_alloc_pt)
+            });
+            if let Err(err) = res {
+                // keep the frames for later requests, the last one may not be mapped
+                for (page, frame) in frames {
+                    let _ = frame.frame_unmap();
+                    mmio.spare.insert(page, frame);
+                }
+                return Err(err);
+            }
+        }
+        for (i, page) in (start..end).step_by(PAGE_SIZE).enumerate() {
+            mmio.mapped.insert(page, vstart + i * PAGE_SIZE);
+        }
+        mmio.next_vaddr += end - start;
+        drop(mmio);
+
+        self.add_region(vstart, start, end - start);
+        Ok(vstart + (paddr - start))
+    }
+}
+
+/// Device memory mapped into the MMIO window of [`MemSpace`].
+pub(crate) struct MmioSpace {
+    next_vaddr: usize,
+    /// Device untyped allocators with their size, keyed by base physical address.
+    untypeds: BTreeMap<usize, (usize, ObjectAllocator)>,
+    /// Frames retyped on the way to a requested page but not mapped yet.
+    spare: BTreeMap<usize, cap::Granule>,
+    /// Virtual addresses of the mapped device pages, keyed by physical address.
+    mapped: BTreeMap<usize, usize>,
+}
+
+impl MmioSpace {
+    pub(crate) const fn new() -> Self {
+        MmioSpace {
+            next_vaddr: MMIO_VADDR_BASE,
+            untypeds: BTreeMap::new(),
+            spare: BTreeMap::new(),
+            mapped: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the virtual address of the device pages `[start, end)` if they
+    /// are all mapped contiguously, or [`None`] if none of them is mapped.
+    fn mapping(&self, start: usize, end: usize) -> sel4::Result<Option<usize>> {
+        let Some(&vstart) = self.mapped.get(&start) else {
+            return match self.mapped.range(start..end).next() {
+                Some(_) => Err(sel4::Error::DeleteFirst),
+                None => Ok(None),
+            };
+        };
+        let contiguous = (start..end)
+            .step_by(PAGE_SIZE)
+            .all(|page| self.mapped.get(&page) == Some(&(vstart + (page - start))));
+        if contiguous {
+            Ok(Some(vstart))
+        } else {
+            Err(sel4::Error::DeleteFirst)
+        }
+    }
+
+    /// Returns the frame of the device page at `paddr`.
+    ///
+    /// Untyped memory is retyped in order, so the frames below `paddr` that
+    /// have not been retyped yet are kept for later requests.
+    fn device_frame(&mut self, paddr: usize) -> sel4::Result<cap::Granule> {
+        if let Some(frame) = self.spare.remove(&paddr) {
+            return Ok(frame);
+        }
+        let (_, (_, allocator)) = self
+            .untypeds
+            .range(..=paddr)
+            .next_back()
+            .filter(|(base, (size, _))| paddr < *base + *size)
+            .ok_or(sel4::Error::FailedLookup)?;
+        loop {
+            let frame = allocator.alloc_page();
+            let frame_paddr = frame.frame_get_address()?;
+            if frame_paddr == paddr {
+                return Ok(frame);
+            }
+            self.spare.insert(frame_paddr, frame);
+            if frame_paddr > paddr {
+                return Err(sel4::Error::FailedLookup);
+            }
+        }
+    }
 }
 
 pub(crate) struct VirtFrameAllocator {
@@ -206,23 +341,52 @@ impl VirtFrameAllocator {
 }
 
 /// Initializes the memory space and sets up the global memory allocator.
-pub(crate) fn init() {
+pub(crate) fn init() -> InitResult {
     MEM_SPACE.init_once(MemSpace::new());
     MEM_SPACE.init();
-    MEM_SPACE.map_area(MEM_START_ADDR, MEM_SIZE);
+    MEM_SPACE.map_area(MEM_START_ADDR, MEM_SIZE)
 }
 
 /// allocate a IPC buffer for new create seL4 thread
 pub(crate) fn alloc_ipc_buffer(
     allocator: &ObjectAllocator,
-) -> sel4::Result<(usize, sel4::cap::Granule)> {
-    MEM_SPACE.alloc_ipc_buffer(allocator)
+) -> InitResult<(usize, sel4::cap::Granule)> {
+    let ipc_cap = allocator.alloc_page();
+    let vaddr = MEM_SPACE.map_ipc_buffer(&ipc_cap, || Ok(allocator.alloc_pt()))?;
+    Ok((vaddr, ipc_cap))
+}
+
+/// Allocates an IPC buffer for a thread of the platform, like
+/// [`alloc_ipc_buffer`] but reporting exhaustion as an error.
+pub(crate) fn try_alloc_ipc_buffer() -> InitResult<(usize, sel4::cap::Granule)> {
+    let ipc_cap = retype(cap::Untyped::from_bits(boot_info().obj_untyped))?;
+    let vaddr = MEM_SPACE.map_ipc_buffer(&ipc_cap, try_alloc_pt)?;
+    Ok((vaddr, ipc_cap))
 }
 
 pub(crate) fn dealloc_ipc_buffer(virt: usize) {
     MEM_SPACE.dealloc_ipc_buffer(virt / PAGE_SIZE);
 }
 
+/// Prints the memory regions of the memory space.
+pub(crate) fn dump() {
+    axplat::console_println!("sysrq: memory regions:");
+    for (vaddr, (pstart, pend)) in MEM_SPACE.regions.lock().iter() {
+        axplat::console_println!(
+            "  va {:#x}: pa [{:#x}, {:#x}), {:#x} bytes",
+            vaddr,
+            pstart,
+            pend,
+            pend - pstart
+        );
+    }
+}
+
+/// Maps a device MMIO region, returns the virtual address of `paddr`.
+pub(crate) fn map_device(paddr: usize, size: usize) -> InitResult<usize> {
+    MEM_SPACE.map_device(paddr, size)
+}
+
 struct MemIfImpl;
 
 #[impl_plat_interface]
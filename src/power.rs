@@ -1,6 +1,6 @@
 use axplat::power::PowerIf;
 
-struct PowerImpl;
+pub(crate) struct PowerImpl;
 
 #[impl_plat_interface]
 impl PowerIf for PowerImpl {
@@ -9,13 +9,19 @@ impl PowerIf for PowerImpl {
     ///
     /// Where `cpu_id` is the logical CPU ID (0, 1, ..., N-1, N is the number of
     /// CPU cores on the platform).
+    ///
+    /// The primary core waits for every secondary one to come up, so a failure
+    /// is reported and the system is powered off.
     #[cfg(feature = "smp")]
     fn cpu_boot(cpu_id: usize, stack_top_paddr: usize) {
-        todo!()
+        if let Err(err) = crate::smp::boot_secondary(cpu_id, stack_top_paddr) {
+            crate::init::report_init_failure(crate::init::InitStage::SecondaryCpu, err);
+        }
     }
 
     /// Shutdown the whole system.
     fn system_off() -> ! {
+        crate::console::flush_early();
         common::root::shutdown()
     }
 }
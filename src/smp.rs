@@ -0,0 +1,54 @@
+//! Secondary CPU bring-up.
+//!
+//! Each secondary "CPU" is a seL4 thread sharing the CSpace and VSpace of the
+//! primary one, pinned to its core with `tcb_set_affinity`. It gets its own
+//! IPC buffer and TLS block, and enters ArceOS through
+//! [`axplat::call_secondary_main`].
+
+use alloc::collections::BTreeMap;
+use kspin::SpinNoIrq;
+
+use crate::error::{InitResult, PlatformInitError};
+use crate::utils::thread::LocalThread;
+
+/// Priority of the secondary CPU threads.
+const SECONDARY_PRIORITY: usize = 100;
+
+/// Secondary CPUs that have been booted, keyed by CPU ID.
+static SECONDARY_CPUS: SpinNoIrq<BTreeMap<usize, LocalThread>> = SpinNoIrq::new(BTreeMap::new());
+
+/// Entry of a secondary CPU thread.
+extern "C" fn secondary_entry(cpu_id: usize) -> ! {
+    axplat::call_secondary_main(cpu_id)
+}
+
+/// Creates and starts the thread of a secondary CPU.
+///
+/// `stack_top_paddr` is the physical address of the top of its boot stack.
+pub(crate) fn boot_secondary(cpu_id: usize, stack_top_paddr: usize) -> InitResult {
+    let stack_top = axplat::mem::phys_to_virt(stack_top_paddr.into()).as_usize();
+    let boot_failed = |err| PlatformInitError::CpuBootFailed { cpu_id, err };
+    let thread = LocalThread::new(SECONDARY_PRIORITY)?;
+    thread
+        .tcb
+        .tcb_set_affinity(cpu_id as _)
+        .map_err(boot_failed)?;
+    #[cfg(feature = "irq")]
+    crate::irq::init_secondary(cpu_id, thread.tcb)?;
+
+    SECONDARY_CPUS.lock().insert(cpu_id, thread);
+    thread
+        .start(secondary_entry, stack_top, cpu_id)
+        .map_err(boot_failed)
+}
+
+/// Returns the seL4 objects of a booted secondary CPU.
+pub(crate) fn secondary_cpu(cpu_id: usize) -> Option<LocalThread> {
+    SECONDARY_CPUS.lock().get(&cpu_id).copied()
+}
+
+/// Early initialization on a secondary CPU: installs its IPC buffer.
+pub(crate) fn init_early_secondary(cpu_id: usize) {
+    let cpu = secondary_cpu(cpu_id).expect("secondary CPU was not booted");
+    cpu.install_ipc_buffer();
+}
@@ -0,0 +1,179 @@
+//! Magic-key debug console.
+//!
+//! Typing the `sysrq.escape` character followed by a bound key prints
+//! diagnostics of the seL4 side or powers the system off. Typing the escape
+//! character twice passes it through as input. The bindings are set in the
+//! `sysrq` config section.
+//!
+//! Sequences received by the console interrupt handler are acted upon in IRQ
+//! context only if the action takes no lock an interrupted thread may hold:
+//! the help, dmesg, IRQ and task dumps, and power-off. The other actions are
+//! deferred until the idle loop waits for IRQs or a reader polls the console,
+//! see [`run_pending`].
+
+use axplat::console_println;
+use axplat::power::PowerIf;
+#[cfg(feature = "irq")]
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::config::sysrq::*;
+
+/// Whether the previous byte was the escape character.
+static ESCAPED: AtomicBool = AtomicBool::new(false);
+
+/// Deferred actions, bit `i` stands for entry `i` of [`SysrqAction::ALL`].
+#[cfg(feature = "irq")]
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+
+/// Actions bound to magic keys.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum SysrqAction {
+    Help,
+    Dmesg,
+    Irqs,
+    IrqStats,
+    Memory,
+    Tasks,
+    Untyped,
+    Slots,
+    PowerOff,
+}
+
+impl SysrqAction {
+    const ALL: [(usize, SysrqAction, &str); 9] = [
+        (HELP_KEY, SysrqAction::Help, "show this help"),
+        (DMESG_KEY, SysrqAction::Dmesg, "print the kernel log"),
+        (IRQ_KEY, SysrqAction::Irqs, "dump the IRQ table"),
+        (IRQ_STATS_KEY, SysrqAction::IrqStats, "dump IRQ statistics"),
+        (MEM_KEY, SysrqAction::Memory, "dump memory regions"),
+        (TASK_KEY, SysrqAction::Tasks, "dump live seL4 tasks"),
+        (UNTYPED_KEY, SysrqAction::Untyped, "dump untyped usage"),
+        (SLOT_KEY, SysrqAction::Slots, "dump slot usage"),
+        (POWEROFF_KEY, SysrqAction::PowerOff, "power off the system"),
+    ];
+
+    #[cfg(feature = "irq")]
+    fn index(self) -> usize {
+        Self::ALL.iter().position(|(_, a, _)| *a == self).unwrap()
+    }
+
+    /// Whether the action can run in IRQ context, i.e. it only takes locks
+    /// that defer IRQs while they are held.
+    #[cfg(feature = "irq")]
+    fn irq_safe(self) -> bool {
+        matches!(
+            self,
+            Self::Help | Self::Dmesg | Self::Irqs | Self::IrqStats | Self::Tasks | Self::PowerOff
+        )
+    }
+
+    fn from_key(key: u8) -> Option<Self> {
+        Self::ALL
+            .iter()
+            .find(|(k, ..)| *k == key as usize)
+            .map(|(_, action, _)| *action)
+    }
+}
+
+/// Result of passing a received byte through the magic-key filter.
+pub(crate) enum Filtered {
+    /// The byte is regular input.
+    Pass(u8),
+    /// The byte was part of a magic-key sequence.
+    Consumed,
+    /// The byte completed a magic-key sequence.
+    Run(SysrqAction),
+}
+
+/// Filters a received byte, detecting magic-key sequences.
+pub(crate) fn filter(c: u8) -> Filtered {
+    if !ESCAPED.swap(false, Ordering::AcqRel) {
+        if c as usize == ESCAPE {
+            ESCAPED.store(true, Ordering::Release);
+            return Filtered::Consumed;
+        }
+        return Filtered::Pass(c);
+    }
+    if c as usize == ESCAPE {
+        return Filtered::Pass(c);
+    }
+    match SysrqAction::from_key(c) {
+        Some(action) => Filtered::Run(action),
+        None => Filtered::Consumed,
+    }
+}
+
+/// Handles a magic-key action received in IRQ context, deferring it to
+/// [`run_pending`] unless it is safe to run right away.
+///
+/// It must not be called with the console locked.
+#[cfg(feature = "irq")]
+pub(crate) fn run_in_irq(action: SysrqAction) {
+    if action.irq_safe() {
+        run(action);
+    } else {
+        PENDING.fetch_or(1 << action.index(), Ordering::AcqRel);
+    }
+}
+
+/// Runs the deferred magic-key actions.
+///
+/// It must not be called in IRQ context or with the console locked.
+pub(crate) fn run_pending() {
+    #[cfg(feature = "irq")]
+    {
+        let pending = PENDING.swap(0, Ordering::AcqRel);
+        for (i, (_, action, _)) in SysrqAction::ALL.iter().enumerate() {
+            if pending & (1 << i) != 0 {
+                run(*action);
+            }
+        }
+    }
+}
+
+/// Runs a magic-key action.
+///
+/// It prints to the console, so it must not be called with the console locked.
+pub(crate) fn run(action: SysrqAction) {
+    match action {
+        SysrqAction::Help => {
+            console_println!("sysrq: escape {:#x}, then:", ESCAPE);
+            for (key, _, desc) in SysrqAction::ALL {
+                console_println!("  {:?}: {}", key as u8 as char, desc);
+            }
+        }
+        SysrqAction::Dmesg => crate::console::write_unlogged(&crate::dmesg::snapshot()),
+        SysrqAction::Irqs => {
+            #[cfg(feature = "irq")]
+            crate::irq::dump();
+            #[cfg(not(feature = "irq"))]
+            console_println!("sysrq: IRQ support is disabled");
+        }
+        SysrqAction::IrqStats => {
+            #[cfg(feature = "irq")]
+            crate::irq::dump_stats();
+            #[cfg(not(feature = "irq"))]
+            console_println!("sysrq: IRQ support is disabled");
+        }
+        SysrqAction::Memory => crate::mem::dump(),
+        SysrqAction::Tasks => {
+            console_println!("sysrq: live seL4 tasks:");
+            for task in crate::task::live_tasks() {
+                console_println!(
+                    "  tid {:#x}: tcb {:#x}, entry {:#x}, stack {:#x}",
+                    task.tid,
+                    task.tcb,
+                    task.entry,
+                    task.stack
+                );
+            }
+        }
+        SysrqAction::Untyped => crate::utils::obj::dump_untyped(),
+        SysrqAction::Slots => crate::utils::obj::dump_slots(),
+        SysrqAction::PowerOff => {
+            console_println!("sysrq: powering off");
+            crate::power::PowerImpl::system_off();
+        }
+    }
+}
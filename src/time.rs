@@ -1,11 +1,55 @@
-use axplat::time::TimeIf;
-use aarch64_cpu::registers::{CNTPCT_EL0, CNTFRQ_EL0};
+//! Monotonic clock and one-shot timer based on the ARM generic timer.
+//!
+//! With the `irq` feature, the single hardware deadline is shared by the
+//! software timers of [`add_timer`] and the one-shot timer of ArceOS.
+//!
+//! The physical timer and its PPI (`timer-irq`) are banked per core, and only
+//! the primary CPU binds the PPI. The hardware deadline is therefore always
+//! armed on the primary CPU and the software timers expire there, while the
+//! one-shot timer of ArceOS is only supported on the primary CPU.
+//!
+//! The wall clock is read from the PL031 RTC at `rtc-paddr` once at
+//! initialization, and kept as an offset to the monotonic clock.
+
 use aarch64_cpu::registers::Readable;
+use aarch64_cpu::registers::{CNTFRQ_EL0, CNTPCT_EL0};
+#[cfg(feature = "irq")]
+use aarch64_cpu::registers::{CNTP_CTL_EL0, CNTP_CVAL_EL0, Writeable};
+#[cfg(feature = "irq")]
+use alloc::{boxed::Box, collections::BTreeMap, collections::BinaryHeap};
+use arm_pl031::Rtc;
+use axplat::time::NANOS_PER_SEC;
+use axplat::time::TimeIf;
+#[cfg(feature = "irq")]
+use core::cmp::Reverse;
+#[cfg(feature = "irq")]
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::{AtomicU64, Ordering};
 use int_ratio::Ratio;
+use kspin::SpinNoIrq;
+use lazyinit::LazyInit;
+
+#[cfg(feature = "irq")]
+use crate::config::devices::TIMER_IRQ;
+use crate::config::devices::RTC_PADDR;
+use crate::error::InitResult;
+#[cfg(feature = "irq")]
+use crate::utils::lock::IrqLock;
 
 static mut CNTPCT_TO_NANOS_RATIO: Ratio = Ratio::zero();
 static mut NANOS_TO_CNTPCT_RATIO: Ratio = Ratio::zero();
 
+/// Wall clock time in nanoseconds since the epoch when the monotonic clock was 0.
+static EPOCH_OFFSET_NANOS: AtomicU64 = AtomicU64::new(0);
+
+static RTC: LazyInit<SpinNoIrq<Rtc>> = LazyInit::new();
+
+/// Returns the current clock time in hardware ticks.
+#[inline]
+pub fn current_ticks() -> u64 {
+    CNTPCT_EL0.get()
+}
+
 /// Converts hardware ticks to nanoseconds.
 #[inline]
 pub fn ticks_to_nanos(ticks: u64) -> u64 {
@@ -19,21 +63,324 @@ pub fn nanos_to_ticks(nanos: u64) -> u64 {
 }
 
 /// Early stage initialization: stores the timer frequency.
-pub fn init_early() {
+pub(crate) fn init_early() {
     let freq = CNTFRQ_EL0.get();
     unsafe {
-        CNTPCT_TO_NANOS_RATIO = Ratio::new(axplat::time::NANOS_PER_SEC as u32, freq as u32);
+        CNTPCT_TO_NANOS_RATIO = Ratio::new(NANOS_PER_SEC as u32, freq as u32);
         NANOS_TO_CNTPCT_RATIO = CNTPCT_TO_NANOS_RATIO.inverse();
     }
 }
 
+/// Later stage initialization: reads the wall clock from the RTC.
+///
+/// The RTC is optional: it does nothing if none is configured, and if it
+/// cannot be mapped the wall clock starts at the epoch.
+pub(crate) fn init_later() -> InitResult {
+    if RTC_PADDR == 0 {
+        return Ok(());
+    }
+    let base = match crate::mem::map_device(RTC_PADDR, 0x1000) {
+        Ok(base) => base,
+        Err(err) => {
+            log::warn!("{}, wall clock starts at the epoch", err);
+            return Ok(());
+        }
+    };
+    let rtc = unsafe { Rtc::new(base as *mut u32) };
+    let epoch_nanos = rtc.get_unix_timestamp() as u64 * NANOS_PER_SEC;
+    EPOCH_OFFSET_NANOS.store(
+        epoch_nanos.saturating_sub(monotonic_nanos()),
+        Ordering::Release,
+    );
+    RTC.init_once(SpinNoIrq::new(rtc));
+    Ok(())
+}
+
+/// Returns the monotonic clock time in nanoseconds.
+fn monotonic_nanos() -> u64 {
+    ticks_to_nanos(current_ticks())
+}
+
+/// Returns the wall clock time in nanoseconds since the epoch.
+pub fn wall_clock_nanos() -> u64 {
+    EPOCH_OFFSET_NANOS.load(Ordering::Acquire) + monotonic_nanos()
+}
+
+/// Sets the wall clock time in nanoseconds since the epoch, and writes it back
+/// to the RTC if there is one.
+pub fn set_wall_clock(epoch_nanos: u64) {
+    EPOCH_OFFSET_NANOS.store(
+        epoch_nanos.saturating_sub(monotonic_nanos()),
+        Ordering::Release,
+    );
+    if RTC.is_inited() {
+        let secs = (epoch_nanos / NANOS_PER_SEC).min(u32::MAX as u64);
+        RTC.lock().set_unix_timestamp(secs as u32);
+    }
+}
+
+/// Deadline of the hardware one-shot timer in ticks, 0 if it is not armed.
+#[cfg(feature = "irq")]
+static ONESHOT_DEADLINE: AtomicU64 = AtomicU64::new(0);
+
+/// Arms the hardware one-shot timer for the absolute monotonic time
+/// `deadline_ns`, replacing the previous deadline.
+///
+/// The deadline is compared against [`current_ticks`], one that has already
+/// passed fires immediately: the `timer-irq` IRQ is raised instead of relying
+/// on the timer condition. Called on a secondary CPU, the IRQ is raised so
+/// that the primary CPU arms its own timer.
+///
+/// It is driven by the software timers of [`add_timer`], which re-arm it for
+/// their earliest deadline.
+#[cfg(feature = "irq")]
+pub(crate) fn set_oneshot_timer(deadline_ns: u64) {
+    let deadline = nanos_to_ticks(deadline_ns).max(1);
+    ONESHOT_DEADLINE.store(deadline, Ordering::Release);
+    if axplat::percpu::this_cpu_id() != 0 {
+        // the primary CPU re-arms the timer when handling the IRQ
+        crate::irq::raise_irq(TIMER_IRQ);
+    } else if deadline <= current_ticks() && crate::irq::raise_irq(TIMER_IRQ) {
+        CNTP_CTL_EL0.write(CNTP_CTL_EL0::ENABLE::CLEAR);
+    } else {
+        CNTP_CVAL_EL0.set(deadline);
+        CNTP_CTL_EL0.write(CNTP_CTL_EL0::ENABLE::SET + CNTP_CTL_EL0::IMASK::CLEAR);
+    }
+}
+
+/// Cancels the hardware one-shot timer, it does nothing if it is not armed.
+///
+/// On a secondary CPU, the timer of the primary CPU stays armed and its expiry
+/// finds nothing to do.
+#[cfg(feature = "irq")]
+pub(crate) fn cancel_oneshot_timer() {
+    ONESHOT_DEADLINE.store(0, Ordering::Release);
+    if axplat::percpu::this_cpu_id() == 0 {
+        CNTP_CTL_EL0.write(CNTP_CTL_EL0::ENABLE::CLEAR);
+    }
+}
+
+/// Returns the deadline of the hardware one-shot timer in nanoseconds, or
+/// [`None`] if it is not armed.
+#[cfg(feature = "irq")]
+pub fn oneshot_deadline() -> Option<u64> {
+    match ONESHOT_DEADLINE.load(Ordering::Acquire) {
+        0 => None,
+        deadline => Some(ticks_to_nanos(deadline)),
+    }
+}
+
+/// Identifier of a software timer.
+#[cfg(feature = "irq")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TimerId(u64);
+
+/// The software timer set by [`TimeIf::set_oneshot_timer`], whose expiry is
+/// dispatched as the `timer-irq` IRQ.
+#[cfg(feature = "irq")]
+const TICK_TIMER: TimerId = TimerId(0);
+
+/// What happens when a software timer expires.
+#[cfg(feature = "irq")]
+enum TimerAction {
+    /// Dispatch the `timer-irq` IRQ to its handler.
+    Tick,
+    Callback(Box<dyn FnOnce() + Send>),
+}
+
+#[cfg(feature = "irq")]
+struct Timer {
+    deadline: u64,
+    action: TimerAction,
+}
+
+/// Software timers multiplexed on the hardware one-shot timer.
+///
+/// The heap holds `(deadline, id)` pairs in nanoseconds. Cancelling or
+/// modifying a timer leaves its old pair in the heap, pairs that no longer
+/// match a timer are dropped when they reach the top. The heap is rebuilt when
+/// they outnumber the live timers.
+#[cfg(feature = "irq")]
+struct TimerQueue {
+    heap: BinaryHeap<Reverse<(u64, TimerId)>>,
+    timers: BTreeMap<TimerId, Timer>,
+    next_id: u64,
+}
+
+#[cfg(feature = "irq")]
+impl TimerQueue {
+    const fn new() -> Self {
+        Self {
+            heap: BinaryHeap::new(),
+            timers: BTreeMap::new(),
+            next_id: TICK_TIMER.0 + 1,
+        }
+    }
+
+    fn insert(&mut self, id: TimerId, deadline: u64, action: TimerAction) {
+        self.timers.insert(id, Timer { deadline, action });
+        self.push(id, deadline);
+    }
+
+    fn modify(&mut self, id: TimerId, deadline: u64) -> bool {
+        let Some(timer) = self.timers.get_mut(&id) else {
+            return false;
+        };
+        timer.deadline = deadline;
+        self.push(id, deadline);
+        true
+    }
+
+    /// Pushes a heap entry, rebuilding the heap from the live timers if most
+    /// of its entries are stale.
+    fn push(&mut self, id: TimerId, deadline: u64) {
+        self.heap.push(Reverse((deadline, id)));
+        if self.heap.len() > 2 * self.timers.len() {
+            self.heap = self
+                .timers
+                .iter()
+                .map(|(&id, timer)| Reverse((timer.deadline, id)))
+                .collect();
+        }
+    }
+
+    /// Returns the earliest deadline, dropping stale heap entries.
+    fn earliest(&mut self) -> Option<u64> {
+        while let Some(&Reverse((deadline, id))) = self.heap.peek() {
+            if self.timers.get(&id).is_some_and(|t| t.deadline == deadline) {
+                return Some(deadline);
+            }
+            self.heap.pop();
+        }
+        None
+    }
+
+    /// Removes the earliest timer if it expired at `now` in ticks.
+    ///
+    /// Deadlines are converted the same way as by [`set_oneshot_timer`], so a
+    /// timer has expired whenever the hardware timer fired for it.
+    fn pop_expired(&mut self, now: u64) -> Option<TimerAction> {
+        if nanos_to_ticks(self.earliest()?).max(1) > now {
+            return None;
+        }
+        let Reverse((_, id)) = self.heap.pop()?;
+        self.timers.remove(&id).map(|t| t.action)
+    }
+
+    /// Re-arms the hardware one-shot timer for the earliest deadline.
+    fn rearm(&mut self) {
+        match self.earliest() {
+            Some(deadline) => set_oneshot_timer(deadline),
+            None => cancel_oneshot_timer(),
+        }
+    }
+}
+
+#[cfg(feature = "irq")]
+static TIMERS: IrqLock<TimerQueue> = IrqLock::new(TimerQueue::new());
+
+/// Runs `f` on the timer queue with IRQs disabled, so that the expiry handling
+/// never runs while the queue is locked, and re-arms the hardware timer.
+#[cfg(feature = "irq")]
+fn update_timers<R>(f: impl FnOnce(&mut TimerQueue) -> R) -> R {
+    let flags = crate::irq::local_irq_save();
+    let ret = {
+        let mut timers = TIMERS.lock();
+        let ret = f(&mut timers);
+        timers.rearm();
+        ret
+    };
+    crate::irq::local_irq_restore(flags);
+    ret
+}
+
+/// Adds a software timer that calls `callback` at the absolute monotonic time
+/// `deadline_ns`.
+///
+/// The callback runs in IRQ context on the primary CPU, a deadline that has
+/// already passed fires immediately.
+#[cfg(feature = "irq")]
+pub fn add_timer(deadline_ns: u64, callback: impl FnOnce() + Send + 'static) -> TimerId {
+    update_timers(|timers| {
+        let id = TimerId(timers.next_id);
+        timers.next_id += 1;
+        timers.insert(id, deadline_ns, TimerAction::Callback(Box::new(callback)));
+        id
+    })
+}
+
+/// Cancels a software timer, returns `false` if it already expired or was
+/// cancelled.
+#[cfg(feature = "irq")]
+pub fn cancel_timer(id: TimerId) -> bool {
+    update_timers(|timers| timers.timers.remove(&id).is_some())
+}
+
+/// Cancels the timer of [`TimeIf::set_oneshot_timer`], returns `false` if it
+/// already expired or was not set.
+#[cfg(feature = "irq")]
+pub fn cancel_tick_timer() -> bool {
+    update_timers(|timers| timers.timers.remove(&TICK_TIMER).is_some())
+}
+
+/// Moves a pending software timer to the absolute monotonic time
+/// `deadline_ns`, returns `false` if it already expired or was cancelled.
+#[cfg(feature = "irq")]
+pub fn modify_timer(id: TimerId, deadline_ns: u64) -> bool {
+    update_timers(|timers| timers.modify(id, deadline_ns))
+}
+
+/// Sets the deadline of the timer of [`TimeIf::set_oneshot_timer`].
+///
+/// Only the primary CPU has the timer IRQ, calls on secondary CPUs are
+/// ignored with a warning.
+#[cfg(feature = "irq")]
+fn set_tick_timer(deadline_ns: u64) {
+    static WARNED: AtomicBool = AtomicBool::new(false);
+    if axplat::percpu::this_cpu_id() != 0 {
+        if !WARNED.swap(true, Ordering::Relaxed) {
+            log::warn!("The one-shot timer is only supported on the primary CPU");
+        }
+        return;
+    }
+    update_timers(|timers| {
+        if !timers.modify(TICK_TIMER, deadline_ns) {
+            timers.insert(TICK_TIMER, deadline_ns, TimerAction::Tick);
+        }
+    });
+}
+
+/// Handles the expiry of the hardware timer: runs the callbacks of the expired
+/// software timers and re-arms the hardware timer for the next deadline.
+///
+/// It returns whether the timer of [`TimeIf::set_oneshot_timer`] expired, in
+/// which case the `timer-irq` handler must be run.
+#[cfg(feature = "irq")]
+pub(crate) fn handle_timer_expiry() -> bool {
+    // the timer interrupt stays asserted after the deadline until it is
+    // re-armed or disabled, so disable it before the expiry is acknowledged
+    cancel_oneshot_timer();
+    let now = current_ticks();
+    let mut tick = false;
+    loop {
+        let action = TIMERS.lock().pop_expired(now);
+        match action {
+            Some(TimerAction::Tick) => tick = true,
+            Some(TimerAction::Callback(callback)) => callback(),
+            None => break,
+        }
+    }
+    TIMERS.lock().rearm();
+    tick
+}
+
 struct TimeIfImpl;
 
 #[impl_plat_interface]
 impl TimeIf for TimeIfImpl {
     /// Returns the current clock time in hardware ticks.
     fn current_ticks() -> u64 {
-        CNTPCT_EL0.get()
+        current_ticks()
     }
 
     /// Converts hardware ticks to nanoseconds.
@@ -49,15 +396,16 @@ impl TimeIf for TimeIfImpl {
     /// Return epoch offset in nanoseconds (wall time offset to monotonic
     /// clock start).
     fn epochoffset_nanos() -> u64 {
-        0
+        EPOCH_OFFSET_NANOS.load(Ordering::Acquire)
     }
 
     /// Set a one-shot timer.
     ///
     /// A timer interrupt will be triggered at the specified monotonic time
-    /// deadline (in nanoseconds).
+    /// deadline (in nanoseconds). It is dispatched as the `timer-irq` IRQ.
+    /// It is one of the software timers sharing the hardware one-shot timer.
     #[cfg(feature = "irq")]
     fn set_oneshot_timer(deadline_ns: u64) {
-        sel4_kit::arch::set_timer(core::time::Duration::from_nanos(deadline_ns));
+        set_tick_timer(deadline_ns);
     }
 }
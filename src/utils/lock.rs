@@ -0,0 +1,75 @@
+//! Locks shared with the IRQ handlers of the platform.
+
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+use kspin::{SpinNoIrq, SpinNoIrqGuard};
+
+/// A [`SpinNoIrq`] that also disables the IRQs of the platform on the current
+/// CPU while it is held.
+///
+/// The dispatcher thread runs IRQ handlers while the primary CPU thread is
+/// preempted, so it must never spin on a lock that thread holds. Instead it
+/// finds IRQs disabled and defers them, they are handled when the lock is
+/// released.
+pub(crate) struct IrqLock<T>(SpinNoIrq<T>);
+
+impl<T> IrqLock<T> {
+    pub(crate) const fn new(data: T) -> Self {
+        Self(SpinNoIrq::new(data))
+    }
+
+    /// Disables IRQs and locks.
+    pub(crate) fn lock(&self) -> IrqLockGuard<'_, T> {
+        let flags = irq_save();
+        IrqLockGuard {
+            guard: ManuallyDrop::new(self.0.lock()),
+            flags,
+        }
+    }
+}
+
+/// Guard of an [`IrqLock`], restores the IRQ state when dropped.
+pub(crate) struct IrqLockGuard<'a, T> {
+    guard: ManuallyDrop<SpinNoIrqGuard<'a, T>>,
+    flags: usize,
+}
+
+impl<T> Deref for IrqLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for IrqLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for IrqLockGuard<'_, T> {
+    fn drop(&mut self) {
+        // unlock first, the deferred IRQs replayed on restore may take the lock
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        irq_restore(self.flags);
+    }
+}
+
+#[cfg(feature = "irq")]
+fn irq_save() -> usize {
+    crate::irq::local_irq_save()
+}
+
+#[cfg(feature = "irq")]
+fn irq_restore(flags: usize) {
+    crate::irq::local_irq_restore(flags);
+}
+
+#[cfg(not(feature = "irq"))]
+fn irq_save() -> usize {
+    0
+}
+
+#[cfg(not(feature = "irq"))]
+fn irq_restore(_flags: usize) {}
@@ -1,3 +1,6 @@
 //! This module provides utilities for managing tasks and objects in the seL4 environment.
 pub mod task;
-pub mod obj;
\ No newline at end of file
+pub mod obj;
+pub(crate) mod lock;
+#[cfg(any(feature = "irq", feature = "smp"))]
+pub(crate) mod thread;
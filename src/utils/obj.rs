@@ -1,24 +1,79 @@
 //! seL4 global object allocator and task object allocator.
 use alloc::vec::Vec;
 use common::ObjectAllocator;
+use core::ops::Range;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use kspin::SpinNoIrq;
 use sel4::{
-    Cap,
+    Cap, CapTypeForObjectOfFixedSize,
     cap::{Granule, PT, Untyped},
 };
+use sel4_kit::slot_manager::LeafSlot;
+
+use crate::bootinfo::boot_info;
+use crate::error::{InitResult, PlatformInitError};
 
 pub(crate) static OBJ_ALLOCATOR: ObjectAllocator = ObjectAllocator::empty();
 
+/// Maximum number of slots kept for the platform's own capabilities.
+const PLATFORM_SLOT_COUNT: usize = 0x200;
+
+/// Slots kept for the platform's own capabilities, see [`alloc_slot`].
+static PLATFORM_SLOTS: SpinNoIrq<SlotPool> = SpinNoIrq::new(SlotPool {
+    free: 0..0,
+    recycled: Vec::new(),
+});
+
+struct SlotPool {
+    free: Range<usize>,
+    recycled: Vec<LeafSlot>,
+}
+
+/// Number of slots allocated directly by the platform.
+static SLOTS_ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+/// Number of slots recycled directly by the platform.
+static SLOTS_RECYCLED: AtomicUsize = AtomicUsize::new(0);
+/// Number of untyped units retyped from the global allocator.
+static UNTYPED_UNITS: AtomicUsize = AtomicUsize::new(0);
+
 pub fn alloc_pt() -> PT {
     OBJ_ALLOCATOR.alloc_pt()
 }
 
+/// Allocates a page table like [`alloc_pt`], reporting exhaustion as an error.
+pub fn try_alloc_pt() -> InitResult<PT> {
+    retype(Cap::from_bits(boot_info().obj_untyped))
+}
+
 pub fn alloc_pages(pn: usize) -> Vec<Granule> {
     OBJ_ALLOCATOR.alloc_pages(pn)
 }
 
 pub fn init() {
-    OBJ_ALLOCATOR.init(Cap::from_bits(23));
+    OBJ_ALLOCATOR.init(Cap::from_bits(boot_info().obj_untyped));
+}
+
+/// Hands the free slot ranges from the boot information to the slot manager.
+///
+/// The end of the first range is kept for the platform's own capabilities,
+/// the slot manager is initialized with the rest of it. The slots of the other
+/// ranges are recycled into the slot manager.
+pub(crate) fn init_slots() -> InitResult {
+    let mut ranges = boot_info().slot_ranges();
+    let first = ranges
+        .next()
+        .filter(|range| range.len() >= 2)
+        .ok_or(PlatformInitError::SlotExhausted)?;
+    let split = first.end - (first.len() / 2).min(PLATFORM_SLOT_COUNT);
+    PLATFORM_SLOTS.lock().free = split..first.end;
+    common::slot::init(first.start..split);
+    for range in ranges {
+        for idx in range {
+            common::slot::recycle_slot(LeafSlot::new(idx));
+        }
+    }
+    common::slot::init_recv_slot();
+    Ok(())
 }
 
 const ALLOC_SIZE_BITS: usize = 21; // 2MB
@@ -29,6 +84,7 @@ pub fn alloc_untyped_unit() -> (Untyped, usize) {
     let cap = match RECYCLED_UNTYPED.lock().pop() {
         Some(cap) => cap,
         None => {
+            UNTYPED_UNITS.fetch_add(1, Ordering::Relaxed);
             OBJ_ALLOCATOR.alloc_untyped(ALLOC_SIZE_BITS)
         },
     };
@@ -38,3 +94,93 @@ pub fn alloc_untyped_unit() -> (Untyped, usize) {
 pub fn recycle_untyped_unit(cap: Untyped) {
     RECYCLED_UNTYPED.lock().push(cap);
 }
+
+/// Allocates an empty slot for a capability of the platform.
+///
+/// The slots come from a pool kept apart from `common::slot`, whose allocator
+/// panics when it runs out, so that exhaustion is reported as an error.
+pub(crate) fn alloc_slot() -> InitResult<LeafSlot> {
+    let mut pool = PLATFORM_SLOTS.lock();
+    let slot = match pool.recycled.pop() {
+        Some(slot) => slot,
+        None => LeafSlot::new(pool.free.next().ok_or(PlatformInitError::SlotExhausted)?),
+    };
+    SLOTS_ALLOCATED.fetch_add(1, Ordering::Relaxed);
+    Ok(slot)
+}
+
+/// Recycles a slot allocated by [`alloc_slot`].
+pub(crate) fn recycle_slot(slot: LeafSlot) {
+    SLOTS_RECYCLED.fetch_add(1, Ordering::Relaxed);
+    PLATFORM_SLOTS.lock().recycled.push(slot);
+}
+
+/// Retypes an object from `untyped` into a slot from [`alloc_slot`].
+///
+/// Unlike the [`ObjectAllocator`] methods, running out of untyped memory or
+/// slots is reported as an error.
+pub(crate) fn retype<T: CapTypeForObjectOfFixedSize>(untyped: Untyped) -> InitResult<Cap<T>> {
+    let slot = alloc_slot()?;
+    untyped
+        .untyped_retype(
+            &T::object_blueprint(),
+            &slot.cnode_abs_cptr(),
+            slot.offset_of_cnode(),
+            1,
+        )
+        .map_err(|err| {
+            recycle_slot(slot);
+            PlatformInitError::UntypedExhausted(err)
+        })?;
+    Ok(slot.cap())
+}
+
+/// Prints the usage of the untyped units handed to tasks.
+pub(crate) fn dump_untyped() {
+    let total = UNTYPED_UNITS.load(Ordering::Relaxed);
+    let recycled = RECYCLED_UNTYPED.lock().len();
+    axplat::console_println!(
+        "sysrq: untyped units of {:#x} bytes: {} retyped, {} in use, {} recycled",
+        1usize << ALLOC_SIZE_BITS,
+        total,
+        total - recycled,
+        recycled
+    );
+}
+
+/// Prints the usage of the free slot ranges.
+///
+/// With the `sel4-debug` feature, the slots in use are counted by identifying
+/// the capability in every slot of the ranges. Otherwise only the usage of the
+/// platform's own pool is known, not the one of `common::slot`.
+pub(crate) fn dump_slots() {
+    #[cfg(feature = "sel4-debug")]
+    for range in boot_info().slot_ranges() {
+        let (start, end) = (range.start, range.end);
+        let used = range
+            .filter(|&idx| sel4::cap::Unspecified::from_bits(idx as _).debug_identify() != 0)
+            .count();
+        axplat::console_println!(
+            "sysrq: slot range [{:#x}, {:#x}): {} in use, {} free",
+            start,
+            end,
+            used,
+            end - start - used
+        );
+    }
+
+    #[cfg(not(feature = "sel4-debug"))]
+    {
+        let allocated = SLOTS_ALLOCATED.load(Ordering::Relaxed);
+        let recycled = SLOTS_RECYCLED.load(Ordering::Relaxed);
+        axplat::console_println!(
+            "sysrq: platform slots: {} allocated, {} recycled, {} in use",
+            allocated,
+            recycled,
+            allocated - recycled
+        );
+        for range in boot_info().slot_ranges() {
+            axplat::console_println!("  free range [{:#x}, {:#x})", range.start, range.end);
+        }
+    }
+}
@@ -15,15 +15,26 @@ use sel4::{
 };
 use sel4_kit::slot_manager::LeafSlot;
 
+use alloc::collections::BTreeMap;
 use alloc::sync::Arc;
+use alloc::vec::Vec;
 
 use super::obj::{alloc_untyped_unit, recycle_untyped_unit};
 use crate::mem::{alloc_ipc_buffer, dealloc_ipc_buffer};
+use crate::utils::lock::IrqLock;
 
-unsafe extern "C" {
-    fn _stdata();
-    fn _etdata();
-    fn _etbss();
+/// Live tasks created by [`create_sel4_task`], keyed by the task pointer.
+///
+/// The sysrq task dump reads it from the console interrupt handler.
+static LIVE_TASKS: IrqLock<BTreeMap<usize, TaskInfo>> = IrqLock::new(BTreeMap::new());
+
+/// Summary of a live [`Sel4Task`].
+#[derive(Clone, Copy, Debug)]
+pub struct TaskInfo {
+    pub tid: usize,
+    pub tcb: usize,
+    pub entry: usize,
+    pub stack: usize,
 }
 
 /// Basic unit representing a task in seL4.
@@ -184,12 +195,25 @@ impl Sel4Task {
 
 pub fn create_sel4_task(tid: usize, entry: usize, stack: usize, tls: usize) -> usize {
     let t = Arc::new(Sel4Task::new(tid, entry, stack, 100, tls).unwrap());
+    let info = TaskInfo {
+        tid: t.tid,
+        tcb: t.tcb.bits() as _,
+        entry: t.entry,
+        stack: t.stack,
+    };
     let ptr = Arc::into_raw(t);
+    LIVE_TASKS.lock().insert(ptr as usize, info);
     ptr as usize
 }
 
 pub fn exit_sel4_task(task_ptr: usize) {
+    LIVE_TASKS.lock().remove(&task_ptr);
     let t = unsafe { Arc::from_raw(task_ptr as *const Sel4Task) };
     log::debug!("exit sel4 task, tid: {}", t.tid);
     t.exit();
 }
+
+/// Returns the summaries of all live tasks created by [`create_sel4_task`].
+pub fn live_tasks() -> Vec<TaskInfo> {
+    LIVE_TASKS.lock().values().copied().collect()
+}
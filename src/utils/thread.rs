@@ -0,0 +1,107 @@
+//! Threads running inside the platform itself.
+//!
+//! Unlike a [`Sel4Task`](super::task::Sel4Task), such a thread shares the
+//! CSpace and VSpace of the primary thread. It gets its own IPC buffer and TLS
+//! block, which the thread installs with [`LocalThread::install_ipc_buffer`].
+
+use common::config::{CNODE_RADIX_BITS, DEFAULT_PARENT_EP};
+use core::alloc::Layout;
+use sel4::{CNodeCapData, cap};
+
+use super::obj::retype;
+use crate::bootinfo::boot_info;
+use crate::error::{InitResult, PlatformInitError};
+use crate::mem::try_alloc_ipc_buffer;
+
+unsafe extern "C" {
+    fn _stdata();
+    fn _etdata();
+    fn _etbss();
+}
+
+/// Size of the thread control block preceding the TLS block (AArch64 TLS variant 1).
+const TLS_TCB_SIZE: usize = 16;
+
+/// Alignment of the TLS block.
+const TLS_ALIGN: usize = 64;
+
+/// seL4 objects backing a thread of the platform.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct LocalThread {
+    pub(crate) tcb: cap::Tcb,
+    pub(crate) ipc_buffer_addr: usize,
+}
+
+impl LocalThread {
+    /// Creates a suspended thread with the given priority.
+    pub(crate) fn new(priority: usize) -> InitResult<Self> {
+        let tcb: cap::Tcb = retype(cap::Untyped::from_bits(boot_info().obj_untyped))?;
+        let (ipc_buffer_addr, ipc_cap) = try_alloc_ipc_buffer()?;
+
+        tcb.tcb_configure(
+            DEFAULT_PARENT_EP.cptr(),
+            sel4::init_thread::slot::CNODE.cap(),
+            CNodeCapData::skip_high_bits(CNODE_RADIX_BITS),
+            sel4::init_thread::slot::VSPACE.cap(),
+            ipc_buffer_addr as _,
+            ipc_cap,
+        )
+        .and_then(|_| tcb.tcb_set_tls_base(alloc_tls() as _))
+        .and_then(|_| {
+            tcb.tcb_set_sched_params(sel4::init_thread::slot::TCB.cap(), 0, priority as _)
+        })
+        .map_err(PlatformInitError::ThreadSetupFailed)?;
+
+        Ok(Self {
+            tcb,
+            ipc_buffer_addr,
+        })
+    }
+
+    /// Starts the thread at `entry` on the stack `stack_top`, with `arg` as
+    /// the first argument.
+    ///
+    /// Like [`Sel4Task::new`](super::task::Sel4Task::new), the thread inherits
+    /// the per-CPU area in `x28` from the calling thread. A secondary CPU sets
+    /// up its own one when it enters ArceOS.
+    pub(crate) fn start(
+        &self,
+        entry: extern "C" fn(usize) -> !,
+        stack_top: usize,
+        arg: usize,
+    ) -> sel4::Result<()> {
+        let mut regs = self.tcb.tcb_read_all_registers(true)?;
+        *regs.pc_mut() = entry as usize as _;
+        *regs.sp_mut() = stack_top as _;
+        *regs.gpr_mut(0) = arg as _;
+        unsafe {
+            core::arch::asm!(
+                "str x28, [{0}]",
+                in(reg) regs.gpr_mut(28),
+                options(nostack, preserves_flags)
+            );
+        }
+        self.tcb.tcb_write_all_registers(false, &mut regs)?;
+        self.tcb.tcb_resume()
+    }
+
+    /// Installs the IPC buffer of this thread, must be called on the thread itself.
+    pub(crate) fn install_ipc_buffer(&self) {
+        sel4::set_ipc_buffer(unsafe { &mut *(self.ipc_buffer_addr as *mut sel4::IpcBuffer) });
+    }
+}
+
+/// Allocates and initializes a TLS block from the `.tdata` and `.tbss`
+/// template, returns the thread pointer.
+fn alloc_tls() -> usize {
+    let tdata_start = _stdata as usize;
+    let tdata_size = _etdata as usize - tdata_start;
+    let tls_size = _etbss as usize - tdata_start;
+    let layout = Layout::from_size_align(TLS_TCB_SIZE + tls_size, TLS_ALIGN).unwrap();
+    let tp = unsafe { alloc::alloc::alloc_zeroed(layout) };
+    assert!(!tp.is_null(), "failed to allocate TLS block");
+    unsafe {
+        core::ptr::copy_nonoverlapping(tdata_start as *const u8, tp.add(TLS_TCB_SIZE), tdata_size);
+    }
+    tp as usize
+}